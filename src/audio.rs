@@ -0,0 +1,104 @@
+use std::{
+    fs::File,
+    io::{Cursor, ErrorKind},
+    path::Path,
+};
+
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error,
+    formats::FormatOptions,
+    io::{MediaSource, MediaSourceStream},
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+/// Decodes the first audio track of a file, calling `on_samples` with the
+/// interleaved samples, channel count and sample rate of each packet.
+pub(crate) fn decode_file(
+    path: &Path,
+    on_samples: impl FnMut(&[f32], usize, u32),
+) -> anyhow::Result<()> {
+    let file = File::open(path)?;
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    decode(Box::new(file), hint, on_samples)
+}
+
+/// Like `decode_file`, for audio that is already in memory.
+pub(crate) fn decode_bytes(
+    data: Vec<u8>,
+    on_samples: impl FnMut(&[f32], usize, u32),
+) -> anyhow::Result<()> {
+    decode(Box::new(Cursor::new(data)), Hint::new(), on_samples)
+}
+
+fn decode(
+    source: Box<dyn MediaSource>,
+    hint: Hint,
+    mut on_samples: impl FnMut(&[f32], usize, u32),
+) -> anyhow::Result<()> {
+    let mss = MediaSourceStream::new(source, Default::default());
+
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(anyhow::anyhow!("No audio track found"))?;
+    let track_id = track.id;
+
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut sample_buf: Option<SampleBuffer<f32>> = None;
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(Error::ResetRequired) => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // Skip over corrupt packets, like most players do
+            Err(Error::DecodeError(e)) => {
+                log::debug!("Skipping corrupt packet: {e}");
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let spec = *decoded.spec();
+        let frames = decoded.capacity() as u64;
+
+        let needed = frames as usize * spec.channels.count();
+        if sample_buf.as_ref().map_or(true, |b| b.capacity() < needed) {
+            sample_buf = Some(SampleBuffer::new(frames, spec));
+        }
+        let buf = sample_buf.as_mut().unwrap();
+        buf.copy_interleaved_ref(decoded);
+
+        on_samples(buf.samples(), spec.channels.count(), spec.rate);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,181 @@
+//! Picks what to play next from the library, for when nobody queued
+//! anything. Songs are scored by how many genres and artists they share
+//! with a seed song.
+//!
+//! `Mode::Similar` takes the best scores in order, `Mode::Weighted` draws at
+//! random with the score as weight, so unrelated songs still come up now and
+//! then. Either way recently played songs are left out, and no artist comes
+//! up twice within `ARTIST_GAP` songs.
+
+use std::{borrow::Cow, collections::HashSet};
+
+use rand::seq::SliceRandom;
+use tiny_http::{Request, Response, ResponseBox};
+
+use crate::{
+    data::{Database, SongEntry},
+    macros::get_query_param,
+};
+
+const GENRE_WEIGHT: f64 = 2.0;
+const ARTIST_WEIGHT: f64 = 1.0;
+/// Lets songs with nothing in common be drawn in `Mode::Weighted`
+const BASE_WEIGHT: f64 = 0.1;
+/// How many of the latest plays count as recently played
+const RECENT_PLAYS: usize = 50;
+/// How many picks have to pass before an artist may come up again
+const ARTIST_GAP: usize = 3;
+const DEFAULT_COUNT: usize = 10;
+const MAX_COUNT: usize = 100;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Mode {
+    Similar,
+    Weighted,
+}
+
+impl Mode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "similar" => Some(Mode::Similar),
+            "weighted" => Some(Mode::Weighted),
+            _ => None,
+        }
+    }
+}
+
+/// The share of values in either list that are in both, ignoring case.
+fn overlap(a: &[Cow<str>], b: &[Cow<str>]) -> f64 {
+    let a: HashSet<String> = a.iter().map(|v| v.to_lowercase()).collect();
+    let b: HashSet<String> = b.iter().map(|v| v.to_lowercase()).collect();
+
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 0.0;
+    }
+
+    a.intersection(&b).count() as f64 / union as f64
+}
+
+fn similarity(seed: &SongEntry, song: &SongEntry) -> f64 {
+    GENRE_WEIGHT * overlap(&seed.genres, &song.genres)
+        + ARTIST_WEIGHT * overlap(&seed.artists, &song.artists)
+}
+
+fn shares_artist(a: &SongEntry, b: &SongEntry) -> bool {
+    a.artists
+        .iter()
+        .any(|x| b.artists.iter().any(|y| x.to_lowercase() == y.to_lowercase()))
+}
+
+struct Candidate {
+    song: SongEntry<'static>,
+    score: f64,
+    is_recent: bool,
+}
+
+/// Up to `count` songs to follow `seed`, without the seed itself and without
+/// the songs `exclude` rejects. Recently played songs are only picked if
+/// there is nothing else left.
+pub(crate) fn pick(
+    db: &Database,
+    seed: Option<&SongEntry>,
+    mode: Mode,
+    count: usize,
+    exclude: &dyn Fn(&SongEntry) -> bool,
+) -> Vec<SongEntry<'static>> {
+    let plays = db.get_plays();
+    let recent: HashSet<&str> = plays
+        .iter()
+        .rev()
+        .take(RECENT_PLAYS)
+        .map(|p| p.song_path.as_str())
+        .collect();
+
+    let mut candidates: Vec<Candidate> = db
+        .get_all_songs()
+        .into_iter()
+        .filter(|s| seed.map_or(true, |seed| seed.song_path != s.song_path) && !exclude(s))
+        .map(|song| Candidate {
+            score: seed.map_or(0.0, |seed| similarity(seed, &song)),
+            is_recent: recent.contains(&song.song_path[..]),
+            song,
+        })
+        .collect();
+
+    let mut picks: Vec<SongEntry<'static>> = Vec::new();
+    while picks.len() < count && !candidates.is_empty() {
+        // The seed is the song played before the first pick
+        let previous: Vec<&SongEntry> = seed
+            .into_iter()
+            .chain(picks.iter().map(|p| p as &SongEntry))
+            .rev()
+            .take(ARTIST_GAP)
+            .collect();
+        let is_repeat = |c: &Candidate| previous.iter().any(|p| shares_artist(p, &c.song));
+
+        // Rather a recent song than none, and rather an artist repeat than a
+        // recent song
+        let tiers: [fn(&Candidate, bool) -> bool; 4] = [
+            |c, repeat| !c.is_recent && !repeat,
+            |c, _| !c.is_recent,
+            |_, repeat| !repeat,
+            |_, _| true,
+        ];
+        let allowed = tiers
+            .iter()
+            .map(|tier| {
+                (0..candidates.len())
+                    .filter(|&i| tier(&candidates[i], is_repeat(&candidates[i])))
+                    .collect::<Vec<usize>>()
+            })
+            .find(|allowed| !allowed.is_empty())
+            .unwrap();
+
+        let index = match mode {
+            Mode::Similar => allowed
+                .iter()
+                .copied()
+                .max_by(|&a, &b| candidates[a].score.total_cmp(&candidates[b].score))
+                .unwrap(),
+            Mode::Weighted => *allowed
+                .choose_weighted(&mut rand::thread_rng(), |&i| candidates[i].score + BASE_WEIGHT)
+                .unwrap(),
+        };
+
+        picks.push(candidates.swap_remove(index).song);
+    }
+
+    picks
+}
+
+/// Serves `api/autoDj?seed=`, songs to play after the song with the path
+/// `seed`, or after the last played song. Takes `?mode=similar` (the
+/// default) or `?mode=weighted` and `?count=`.
+pub(crate) fn auto_dj(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_auth!(db, req);
+
+    let url = req.url();
+    let Some(mode) = Mode::parse(get_query_param(url, "mode").as_deref().unwrap_or("similar")) else {
+        return Response::from_string("Unknown mode").with_status_code(400).boxed();
+    };
+    let count = get_query_param(url, "count")
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(DEFAULT_COUNT)
+        .min(MAX_COUNT);
+
+    let seed = match get_query_param(url, "seed") {
+        Some(path) => match db.get_song_by_path(&path) {
+            Some(song) => Some(song),
+            None => return Response::from_string("").with_status_code(404).boxed(),
+        },
+        None => db
+            .get_plays()
+            .last()
+            .and_then(|p| db.get_song_by_path(&p.song_path)),
+    };
+
+    let picks = pick(db, seed.as_ref(), mode, count, &|_| false);
+
+    crate::to_json!(&picks)
+}
@@ -0,0 +1,264 @@
+//! Backups of a whole instance as one tar archive: the stores, optionally
+//! `./songs/`, and a `manifest.json` with the SHA-256 of every file.
+//!
+//! Stores are copied under their lock first and the copies are what gets
+//! hashed and archived, so a running server can't change them in between.
+//!
+//! A restore unpacks into `./.restore/` first and only touches the live
+//! files once every checksum in the manifest matches.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const MANIFEST: &str = "manifest.json";
+const MANIFEST_VERSION: u32 = 1;
+const STAGING_DIR: &str = "./.restore";
+/// Live files a restore replaced, put back if it fails halfway
+const PREVIOUS_DIR: &str = "./.restore/.previous";
+const SNAPSHOT_DIR: &str = "./.backup";
+const STORES: &[&str] = &["songs.csv", "users.csv", "whitelist.csv", "tokens.csv", "plays.csv", "ratings.csv"];
+
+#[derive(Serialize, Deserialize)]
+struct ManifestFile {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    created_at: u64,
+    include_audio: bool,
+    files: Vec<ManifestFile>,
+}
+
+fn hash_file(path: &Path) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+fn list_files(include_audio: bool) -> anyhow::Result<Vec<String>> {
+    let mut files: Vec<String> = STORES
+        .iter()
+        .filter(|s| Path::new(s).exists())
+        .map(|s| s.to_string())
+        .collect();
+
+    if include_audio {
+        let mut songs = Vec::new();
+        for entry in fs::read_dir("./songs")? {
+            let entry = entry?;
+            // Songs scanned in place are links, back up what they point to
+            if entry.path().is_file() {
+                songs.push(format!("songs/{}", entry.file_name().to_string_lossy()));
+            }
+        }
+        songs.sort();
+        files.extend(songs);
+    }
+
+    Ok(files)
+}
+
+/// Writes a backup to `out`, returning the number of files in it.
+pub(crate) fn create(out: &Path, include_audio: bool) -> anyhow::Result<usize> {
+    let _ = fs::remove_dir_all(SNAPSHOT_DIR);
+    fs::create_dir_all(SNAPSHOT_DIR)?;
+
+    let result = write_archive(out, include_audio);
+
+    let _ = fs::remove_dir_all(SNAPSHOT_DIR);
+
+    result
+}
+
+/// Where a file is read from for the archive: the snapshot of a store, or
+/// the audio file itself.
+fn get_source_path(path: &str) -> PathBuf {
+    if STORES.contains(&path) {
+        PathBuf::from(SNAPSHOT_DIR).join(path)
+    } else {
+        PathBuf::from(path)
+    }
+}
+
+fn write_archive(out: &Path, include_audio: bool) -> anyhow::Result<usize> {
+    let files = list_files(include_audio)?;
+    for store in files.iter().filter(|f| STORES.contains(&f.as_str())) {
+        crate::data::copy_store_file(store, &get_source_path(store))?;
+    }
+
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION,
+        created_at: crate::jobs::now(),
+        include_audio,
+        files: Vec::new(),
+    };
+    for path in &files {
+        let source = get_source_path(path);
+        manifest.files.push(ManifestFile {
+            path: path.clone(),
+            size: fs::metadata(&source)?.len(),
+            sha256: hash_file(&source)?,
+        });
+    }
+
+    let mut builder = tar::Builder::new(BufWriter::new(File::create(out)?));
+    builder.follow_symlinks(true);
+
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created_at);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST, &manifest_json[..])?;
+
+    for path in &files {
+        builder.append_path_with_name(get_source_path(path), path)?;
+    }
+
+    builder.into_inner()?.into_inner()?.sync_all()?;
+
+    Ok(files.len())
+}
+
+/// Only plain relative paths are unpacked, nothing may escape the staging
+/// directory.
+fn is_safe_path(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Restores a backup over the live files. Returns the number of restored
+/// files, or an error after putting back any live file it already replaced.
+pub(crate) fn restore(archive: &Path) -> anyhow::Result<usize> {
+    let _ = fs::remove_dir_all(STAGING_DIR);
+    fs::create_dir_all(STAGING_DIR)?;
+
+    let result = unpack_and_verify(archive).and_then(|manifest| {
+        let mut replaced = Vec::new();
+        if let Err(e) = replace_live_files(&manifest, &mut replaced) {
+            roll_back(&replaced);
+            return Err(e);
+        }
+
+        Ok(manifest.files.len())
+    });
+
+    let _ = fs::remove_dir_all(STAGING_DIR);
+
+    result
+}
+
+/// A live file the restore replaced, and the copy it was saved to, `None` if
+/// the file didn't exist before.
+type Replaced = (String, Option<PathBuf>);
+
+fn replace_live_files(manifest: &Manifest, replaced: &mut Vec<Replaced>) -> anyhow::Result<()> {
+    for file in &manifest.files {
+        let staged = PathBuf::from(STAGING_DIR).join(&file.path);
+
+        let previous = if Path::new(&file.path).exists() {
+            let previous = PathBuf::from(PREVIOUS_DIR).join(&file.path);
+            if let Some(parent) = previous.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&file.path, &previous)?;
+            Some(previous)
+        } else {
+            None
+        };
+        replaced.push((file.path.clone(), previous));
+
+        if STORES.contains(&file.path.as_str()) {
+            crate::data::replace_store_file(&file.path, &staged)?;
+        } else {
+            fs::create_dir_all("./songs")?;
+            fs::rename(&staged, &file.path)?;
+        }
+        log::info!("Restored {}", file.path);
+    }
+
+    Ok(())
+}
+
+fn roll_back(replaced: &[Replaced]) {
+    for (path, previous) in replaced.iter().rev() {
+        let result = match previous {
+            Some(previous) if STORES.contains(&path.as_str()) => {
+                crate::data::replace_store_file(path, previous)
+            }
+            Some(previous) => fs::copy(previous, path).map(|_| ()),
+            None => fs::remove_file(path),
+        };
+
+        match result {
+            Ok(()) => log::info!("Rolled back {path}"),
+            Err(e) => log::error!("Failed to roll back {path}: {e:?}"),
+        }
+    }
+}
+
+fn unpack_and_verify(archive: &Path) -> anyhow::Result<Manifest> {
+    let mut tar = tar::Archive::new(BufReader::new(File::open(archive)?));
+    let mut manifest: Option<Manifest> = None;
+
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        if !is_safe_path(&path) {
+            return Err(anyhow::anyhow!("Unsafe path {} in backup", path.display()));
+        }
+
+        if path == Path::new(MANIFEST) {
+            manifest = Some(serde_json::from_reader(&mut entry)?);
+            continue;
+        }
+
+        let staged = PathBuf::from(STAGING_DIR).join(&path);
+        if let Some(parent) = staged.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        io::copy(&mut entry, &mut File::create(&staged)?)?;
+    }
+
+    let Some(manifest) = manifest else {
+        return Err(anyhow::anyhow!("Backup has no {MANIFEST}"));
+    };
+    if manifest.version != MANIFEST_VERSION {
+        return Err(anyhow::anyhow!("Unsupported backup version {}", manifest.version));
+    }
+
+    for file in &manifest.files {
+        let path = Path::new(&file.path);
+        let is_known = STORES.contains(&file.path.as_str())
+            || (path.starts_with("songs") && path.components().count() == 2);
+        if !is_safe_path(path) || !is_known {
+            return Err(anyhow::anyhow!("Unexpected file {} in manifest", file.path));
+        }
+
+        let staged = PathBuf::from(STAGING_DIR).join(path);
+        if !staged.is_file() {
+            return Err(anyhow::anyhow!("{} is missing from the backup", file.path));
+        }
+        if hash_file(&staged)? != file.sha256 {
+            return Err(anyhow::anyhow!("Checksum mismatch for {}", file.path));
+        }
+    }
+
+    Ok(manifest)
+}
@@ -0,0 +1,213 @@
+//! On-disk cache of MusicBrainz lookups, one JSON file per normalised query
+//! in `./cache/musicbrainz/`. Lookups that found nothing are cached too, but
+//! for a shorter time.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const CACHE_DIR: &str = "./cache/musicbrainz";
+const HIT_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+const MISS_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry<T> {
+    key: String,
+    fetched_at: u64,
+    value: Option<T>,
+}
+
+impl<T> CacheEntry<T> {
+    fn is_expired(&self, now: u64) -> bool {
+        let ttl = if self.value.is_some() { HIT_TTL } else { MISS_TTL };
+        now.saturating_sub(self.fetched_at) > ttl.as_secs()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Queries differing only in case or whitespace share a cache entry.
+pub(crate) fn normalize_key(kind: &str, query: &str) -> String {
+    let query = query
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    format!("{kind}:{query}")
+}
+
+fn get_cache_path(dir: &Path, key: &str) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(key.as_bytes());
+    let hash = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+
+    dir.join(format!("{hash}.json"))
+}
+
+/// Returns the cached result for `query`, or calls `fetch` and caches what
+/// it returns. Errors from `fetch` are not cached.
+pub(crate) fn get_or_fetch<T: Serialize + DeserializeOwned>(
+    kind: &str,
+    query: &str,
+    fetch: impl FnOnce() -> anyhow::Result<Option<T>>,
+) -> anyhow::Result<Option<T>> {
+    get_or_fetch_in(Path::new(CACHE_DIR), kind, query, fetch)
+}
+
+fn get_or_fetch_in<T: Serialize + DeserializeOwned>(
+    dir: &Path,
+    kind: &str,
+    query: &str,
+    fetch: impl FnOnce() -> anyhow::Result<Option<T>>,
+) -> anyhow::Result<Option<T>> {
+    let key = normalize_key(kind, query);
+    let path = get_cache_path(dir, &key);
+
+    if let Ok(content) = fs::read_to_string(&path) {
+        match serde_json::from_str::<CacheEntry<T>>(&content) {
+            Ok(entry) if entry.key == key && !entry.is_expired(now()) => {
+                log::debug!("MusicBrainz cache hit for {key}");
+                return Ok(entry.value);
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Ignoring unreadable cache entry {}: {e}", path.display()),
+        }
+    }
+
+    let value = fetch()?;
+
+    let entry = CacheEntry {
+        key,
+        fetched_at: now(),
+        value,
+    };
+    let written = fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&path, serde_json::to_string(&entry).unwrap_or_default()));
+    if let Err(e) = written {
+        log::warn!("Failed to write cache entry {}: {e}", path.display());
+    }
+
+    Ok(entry.value)
+}
+
+/// Drops the cached result for `query`, so the next lookup fetches it again.
+pub(crate) fn invalidate(kind: &str, query: &str) {
+    invalidate_in(Path::new(CACHE_DIR), kind, query);
+}
+
+fn invalidate_in(dir: &Path, kind: &str, query: &str) {
+    let _ = fs::remove_file(get_cache_path(dir, &normalize_key(kind, query)));
+}
+
+/// Removes cache entries, either all of them or only the expired ones.
+/// Returns how many were removed.
+pub(crate) fn purge(expired_only: bool) -> usize {
+    let Ok(dir) = fs::read_dir(CACHE_DIR) else {
+        return 0;
+    };
+
+    let now = now();
+    let mut removed = 0;
+
+    for entry in dir.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+
+        if expired_only {
+            let expired = fs::read_to_string(&path)
+                .ok()
+                .and_then(|c| serde_json::from_str::<CacheEntry<serde_json::Value>>(&c).ok())
+                .map_or(true, |e| e.is_expired(now));
+            if !expired {
+                continue;
+            }
+        }
+
+        if fs::remove_file(&path).is_ok() {
+            removed += 1;
+        }
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hit_skips_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let first = get_or_fetch_in(dir.path(), "test", "hit", || Ok(Some(1u32))).unwrap();
+        let second = get_or_fetch_in(dir.path(), "test", "HIT", || -> anyhow::Result<Option<u32>> {
+            panic!("Fetched despite a cached entry")
+        })
+        .unwrap();
+
+        assert_eq!(first, Some(1));
+        assert_eq!(second, Some(1));
+    }
+
+    #[test]
+    fn expired_entry_is_fetched_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = normalize_key("test", "expired");
+
+        let entry = CacheEntry {
+            key: key.clone(),
+            fetched_at: now() - HIT_TTL.as_secs() - 1,
+            value: Some(1u32),
+        };
+        fs::write(get_cache_path(dir.path(), &key), serde_json::to_string(&entry).unwrap()).unwrap();
+
+        let value = get_or_fetch_in(dir.path(), "test", "expired", || Ok(Some(2u32))).unwrap();
+
+        assert_eq!(value, Some(2));
+    }
+
+    #[test]
+    fn misses_expire_sooner() {
+        let fetched_at = now() - MISS_TTL.as_secs() - 1;
+        let hit = CacheEntry {
+            key: String::new(),
+            fetched_at,
+            value: Some(1u32),
+        };
+        let miss = CacheEntry::<u32> {
+            key: String::new(),
+            fetched_at,
+            value: None,
+        };
+
+        assert!(!hit.is_expired(now()));
+        assert!(miss.is_expired(now()));
+    }
+
+    #[test]
+    fn invalidate_forces_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+
+        get_or_fetch_in(dir.path(), "test", "invalidate", || Ok(Some(1u32))).unwrap();
+        invalidate_in(dir.path(), "test", "invalidate");
+        let value = get_or_fetch_in(dir.path(), "test", "invalidate", || Ok(Some(2u32))).unwrap();
+
+        assert_eq!(value, Some(2));
+    }
+}
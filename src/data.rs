@@ -1,14 +1,35 @@
-use std::{borrow::Cow, fs::{self, File}, io::{BufReader, BufWriter}, sync::{Arc, RwLock}};
+use std::{borrow::Cow, fs::{self, File}, io::{BufReader, BufWriter}, path::Path, sync::{Arc, Mutex, RwLock}, time::SystemTime};
 
+use fs2::FileExt;
 use serde::Serialize;
 
-#[derive(Serialize)]
+use crate::jobs::{JobKind, JobStatus};
+
+#[derive(Clone, Default, Serialize)]
 pub(crate) struct SongEntry<'a> {
     pub title: Cow<'a, str>,
     pub album: Cow<'a, str>,
+    /// Track number on the album
+    pub track: Option<u32>,
+    /// Read from the file when the song is added, so listings don't have to
+    pub duration_secs: Option<u64>,
     pub artists: Vec<Cow<'a, str>>,
     pub genres: Vec<Cow<'a, str>>,
     pub song_path: Cow<'a, str>,
+    /// ReplayGain 2.0 gain in dB, relative to -18 LUFS
+    pub track_gain: Option<f64>,
+    /// Linear true peak, 1.0 is full scale
+    pub track_peak: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
+    /// MusicBrainz IDs, when the song was matched while probing
+    pub recording_mbid: Option<Cow<'a, str>>,
+    pub release_group_mbid: Option<Cow<'a, str>>,
+    pub artist_mbids: Vec<Cow<'a, str>>,
+    /// Where the scanner found the song, if it wasn't uploaded
+    pub source: Option<Cow<'a, str>>,
+    /// SHA-256 of the decoded audio, which writing tags leaves unchanged
+    pub content_hash: Option<Cow<'a, str>>,
 }
 
 #[derive(Clone)]
@@ -16,14 +37,22 @@ pub struct Database {
     songs: Arc<RwLock<SongDatabase>>,
     passwords: Arc<RwLock<PasswordDatabase>>,
     whitelist: Arc<RwLock<WhitelistDatabase>>,
+    share_tokens: Arc<RwLock<ShareTokenDatabase>>,
+    jobs: Arc<RwLock<JobDatabase>>,
+    plays: Arc<RwLock<PlayDatabase>>,
+    ratings: Arc<RwLock<RatingDatabase>>,
 }
 
 impl Database {
-    pub fn open(song_path: String, password_path: String, whitelist_path: String) -> Self {
+    pub fn open(song_path: String, password_path: String, whitelist_path: String, share_token_path: String, job_path: String, play_path: String, rating_path: String) -> Self {
         Database {
             songs: Arc::new(RwLock::new(SongDatabase::new(song_path))),
             passwords: Arc::new(RwLock::new(PasswordDatabase::new(password_path))),
-            whitelist: Arc::new(RwLock::new(WhitelistDatabase::new(whitelist_path))) 
+            whitelist: Arc::new(RwLock::new(WhitelistDatabase::new(whitelist_path))),
+            share_tokens: Arc::new(RwLock::new(ShareTokenDatabase::new(share_token_path))),
+            jobs: Arc::new(RwLock::new(JobDatabase::new(job_path))),
+            plays: Arc::new(RwLock::new(PlayDatabase::new(play_path))),
+            ratings: Arc::new(RwLock::new(RatingDatabase::new(rating_path))),
         }
     }
 
@@ -32,20 +61,61 @@ impl Database {
         inner.is_allowed(ip)
     }
 
+    /// The user who created a share token, if it is valid.
+    pub(crate) fn get_share_token_user(&self, token: &str) -> Option<String> {
+        let inner = self.share_tokens.read().unwrap();
+        inner.get_user(token)
+    }
+
+    pub(crate) fn add_share_token(&self, token: &str, user: &str) {
+        let mut inner = self.share_tokens.write().unwrap();
+        inner.add_token(token, user);
+    }
+
     pub fn get_song_by_title_and_artist<'a>(&self, title: &str, artist: &str) -> Option<SongEntry<'static>> {
         let inner = self.songs.read().unwrap();
         inner.get_song_by_title_and_artist(title, artist)
     }
 
-    pub fn get_all_json(&self) -> String {
+    pub(crate) fn get_song_index(&self) -> Arc<SongIndex> {
         let inner = self.songs.read().unwrap();
-        inner.get_all_json()
+        inner.index()
+    }
+
+    pub fn get_song_by_path(&self, song_path: &str) -> Option<SongEntry<'static>> {
+        let inner = self.songs.read().unwrap();
+        inner.index().songs.iter().find(|s| s.song_path == song_path).cloned()
+    }
+
+    pub fn get_all_songs(&self) -> Vec<SongEntry<'static>> {
+        let inner = self.songs.read().unwrap();
+        inner.get_all()
     }
 
     pub fn add_song(&self, song: &SongEntry) {
         let mut inner = self.songs.write().unwrap();
         inner.add_song(song);
     }
+
+    pub fn update_songs(&self, songs: &[SongEntry]) {
+        let mut inner = self.songs.write().unwrap();
+        inner.update_songs(songs);
+    }
+
+    /// Changes one song as it is stored right now, for when the change took
+    /// long to compute and the song may have been edited in the meantime.
+    /// Returns the updated song, or `None` if it no longer exists.
+    pub(crate) fn update_song(&self, song_path: &str, update: impl FnOnce(&mut SongEntry<'static>)) -> Option<SongEntry<'static>> {
+        let mut inner = self.songs.write().unwrap();
+        inner.update_song(song_path, update)
+    }
+
+    /// Removes the songs `keep` returns false for, deciding on the rows as
+    /// they are stored right now.
+    pub(crate) fn retain_songs(&self, keep: impl FnMut(&SongEntry<'static>) -> bool) {
+        let mut inner = self.songs.write().unwrap();
+        inner.retain(keep);
+    }
     
     pub(crate) fn add_user(&self, user: &str, base64_pass: &str) {
         let mut inner = self.passwords.write().unwrap();
@@ -61,6 +131,342 @@ impl Database {
         let mut inner = self.passwords.write().unwrap();
         inner.update_user(user, base64_pass);
     }
+
+    pub(crate) fn get_api_password(&self, user: &str) -> Option<String> {
+        let inner = self.passwords.read().unwrap();
+        inner.get_api_password(user)
+    }
+
+    pub(crate) fn set_api_password(&self, user: &str, api_pass: &str) -> bool {
+        let mut inner = self.passwords.write().unwrap();
+        inner.set_api_password(user, api_pass)
+    }
+
+    pub(crate) fn add_job(&self, kind: JobKind, song_path: &str, now: u64) -> JobEntry {
+        let mut inner = self.jobs.write().unwrap();
+        inner.add_job(kind, song_path, now)
+    }
+
+    pub(crate) fn get_jobs(&self) -> Vec<JobEntry> {
+        let inner = self.jobs.read().unwrap();
+        inner.get_all()
+    }
+
+    pub(crate) fn add_play(&self, play: &PlayEntry) {
+        let mut inner = self.plays.write().unwrap();
+        inner.add_play(play);
+    }
+
+    /// Every play, oldest first.
+    pub(crate) fn get_plays(&self) -> Vec<PlayEntry> {
+        let inner = self.plays.read().unwrap();
+        inner.get_all()
+    }
+
+    /// The ratings and favorites of one user.
+    pub(crate) fn get_ratings(&self, user: &str) -> Vec<RatingEntry> {
+        let inner = self.ratings.read().unwrap();
+        inner.get_all().into_iter().filter(|r| r.user == user).collect()
+    }
+
+    /// Changes the rating and/or favorite flag of a song for a user.
+    pub(crate) fn set_rating(&self, user: &str, song_path: &str, rating: Option<u8>, favorite: Option<bool>) -> RatingEntry {
+        let mut inner = self.ratings.write().unwrap();
+        inner.set_rating(user, song_path, rating, favorite)
+    }
+
+    /// Replaces all jobs at once, the queue is small enough to rewrite.
+    pub(crate) fn update_jobs(&self, update: impl FnOnce(&mut Vec<JobEntry>)) {
+        let mut inner = self.jobs.write().unwrap();
+        inner.update(update);
+    }
+
+    /// Marks the oldest job that is due as running and returns it. The queue
+    /// is only rewritten if there was one.
+    pub(crate) fn claim_job(&self, now: u64) -> Option<JobEntry> {
+        let mut inner = self.jobs.write().unwrap();
+        inner.claim(now)
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct JobEntry {
+    pub id: u64,
+    pub kind: JobKind,
+    pub song_path: String,
+    pub status: JobStatus,
+    pub attempts: u32,
+    /// Unix time the job may run at, pushed back after each failure
+    pub next_attempt: u64,
+    pub error: String,
+    pub created_at: u64,
+    /// What a finished job found, for jobs that report something, like the
+    /// report of a scan
+    pub result: Option<serde_json::Value>,
+}
+
+impl JobEntry {
+    pub fn is_due(&self, now: u64) -> bool {
+        self.status == JobStatus::Pending && self.next_attempt <= now
+    }
+}
+
+struct JobDatabase {
+    path: String,
+}
+
+const JOB_HEADER: [&str; 9] = ["id", "kind", "song_path", "status", "attempts", "next_attempt", "error", "created_at", "result"];
+
+impl JobDatabase {
+    pub fn new(path: String) -> Self {
+        if !Path::new(&path).exists() {
+            fs::write(&path, format!("{}\n", JOB_HEADER.join(","))).unwrap();
+        }
+        recover(&path, true);
+        JobDatabase { path }
+    }
+    pub fn add_job(&mut self, kind: JobKind, song_path: &str, now: u64) -> JobEntry {
+        let _lock = lock_store(&self.path);
+        let id = self.get_all().iter().map(|j| j.id).max().unwrap_or(0) + 1;
+        let job = JobEntry {
+            id,
+            kind,
+            song_path: song_path.to_string(),
+            status: JobStatus::Pending,
+            attempts: 0,
+            next_attempt: now,
+            error: String::new(),
+            created_at: now,
+            result: None,
+        };
+
+        let mut db = self.open_database_write();
+        db.write_record(&job_to_record(&job)).unwrap();
+        sync_append(db);
+
+        job
+    }
+    pub fn update(&mut self, update: impl FnOnce(&mut Vec<JobEntry>)) {
+        let _lock = lock_store(&self.path);
+        let mut all = self.get_all();
+        update(&mut all);
+        self.write_all(&all);
+    }
+    pub fn claim(&mut self, now: u64) -> Option<JobEntry> {
+        let _lock = lock_store(&self.path);
+        let mut all = self.get_all();
+        let job = all.iter_mut().find(|j| j.is_due(now))?;
+        job.status = JobStatus::Running;
+        job.attempts += 1;
+        let job = job.clone();
+        self.write_all(&all);
+        Some(job)
+    }
+    fn get_all(&self) -> Vec<JobEntry> {
+        let mut db = self.open_database_read();
+        db.records()
+            .filter_map(|r| r.ok())
+            .filter_map(|r| {
+                Some(JobEntry {
+                    id: r.get(0)?.parse().ok()?,
+                    kind: JobKind::parse(r.get(1)?)?,
+                    song_path: r.get(2)?.to_string(),
+                    status: JobStatus::parse(r.get(3)?)?,
+                    attempts: r.get(4)?.parse().ok()?,
+                    next_attempt: r.get(5)?.parse().ok()?,
+                    error: r.get(6)?.to_string(),
+                    created_at: r.get(7)?.parse().ok()?,
+                    // Missing in queues from before results were kept
+                    result: r.get(8).and_then(|r| serde_json::from_str(r).ok()),
+                })
+            })
+            .collect()
+    }
+    fn write_all(&mut self, all: &[JobEntry]) {
+        {
+            let mut db = self.open_temp_database_write();
+            db.write_record(JOB_HEADER).unwrap();
+            for job in all {
+                db.write_record(&job_to_record(job)).unwrap();
+            }
+        }
+
+        self.copy_temp_database();
+    }
+    fn open_database_read(&self) -> csv::Reader<BufReader<File>> {
+        let rdr = csv::ReaderBuilder::new().flexible(true).from_reader(BufReader::new(File::open(&self.path).unwrap()));
+        rdr
+    }
+    fn open_database_write(&mut self) -> csv::Writer<BufWriter<File>> {
+        let rdr = csv::WriterBuilder::new().flexible(true).from_writer(BufWriter::new(File::options().append(true).open(&self.path).unwrap()));
+        rdr
+    }
+    fn open_temp_database_write(&mut self) -> csv::Writer<BufWriter<File>> {
+        let rdr = csv::WriterBuilder::new().from_writer(BufWriter::new(File::create(&format!("{}.tmp", self.path)).unwrap()));
+        rdr
+    }
+    fn copy_temp_database(&mut self) {
+        replace_with_temp(&self.path);
+    }
+}
+
+fn job_to_record(job: &JobEntry) -> Vec<String> {
+    vec![
+        job.id.to_string(),
+        job.kind.as_str().to_string(),
+        job.song_path.clone(),
+        job.status.as_str().to_string(),
+        job.attempts.to_string(),
+        job.next_attempt.to_string(),
+        job.error.clone(),
+        job.created_at.to_string(),
+        job.result.as_ref().map(|r| r.to_string()).unwrap_or_default(),
+    ]
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct PlayEntry {
+    pub song_path: String,
+    /// The user, or for `/data/` the IP or share token, as `get_data_access`
+    /// reports it
+    pub listener: String,
+    pub played_at: u64,
+    /// `data`, `scrobble` or `subsonic`
+    pub source: String,
+}
+
+struct PlayDatabase {
+    path: String,
+}
+
+impl PlayDatabase {
+    pub fn new(path: String) -> Self {
+        if !Path::new(&path).exists() {
+            fs::write(&path, "song_path,listener,played_at,source\n").unwrap();
+        }
+        recover(&path, true);
+        PlayDatabase { path }
+    }
+    pub fn add_play(&mut self, play: &PlayEntry) {
+        let _lock = lock_store(&self.path);
+        let mut db = self.open_database_write();
+        db.write_record(&[&play.song_path, &play.listener, &play.played_at.to_string(), &play.source]).unwrap();
+        sync_append(db);
+    }
+    fn get_all(&self) -> Vec<PlayEntry> {
+        let mut db = self.open_database_read();
+        db.records()
+            .filter_map(|r| r.ok())
+            .filter_map(|r| {
+                Some(PlayEntry {
+                    song_path: r.get(0)?.to_string(),
+                    listener: r.get(1)?.to_string(),
+                    played_at: r.get(2)?.parse().ok()?,
+                    source: r.get(3)?.to_string(),
+                })
+            })
+            .collect()
+    }
+    fn open_database_read(&self) -> csv::Reader<BufReader<File>> {
+        let rdr = csv::ReaderBuilder::new().from_reader(BufReader::new(File::open(&self.path).unwrap()));
+        rdr
+    }
+    fn open_database_write(&mut self) -> csv::Writer<BufWriter<File>> {
+        let rdr = csv::WriterBuilder::new().from_writer(BufWriter::new(File::options().append(true).open(&self.path).unwrap()));
+        rdr
+    }
+}
+
+#[derive(Clone, Default, Serialize)]
+pub(crate) struct RatingEntry {
+    #[serde(skip)]
+    pub user: String,
+    pub song_path: String,
+    /// 1 to 5 stars, 0 when unrated
+    pub rating: u8,
+    pub favorite: bool,
+}
+
+struct RatingDatabase {
+    path: String,
+}
+
+impl RatingDatabase {
+    pub fn new(path: String) -> Self {
+        if !Path::new(&path).exists() {
+            fs::write(&path, "user,song_path,rating,favorite\n").unwrap();
+        }
+        recover(&path, false);
+        RatingDatabase { path }
+    }
+    pub fn set_rating(&mut self, user: &str, song_path: &str, rating: Option<u8>, favorite: Option<bool>) -> RatingEntry {
+        let _lock = lock_store(&self.path);
+        let mut all = self.get_all();
+
+        let index = match all.iter().position(|r| r.user == user && r.song_path == song_path) {
+            Some(index) => index,
+            None => {
+                all.push(RatingEntry {
+                    user: user.to_string(),
+                    song_path: song_path.to_string(),
+                    ..Default::default()
+                });
+                all.len() - 1
+            }
+        };
+
+        let entry = &mut all[index];
+        if let Some(rating) = rating {
+            entry.rating = rating;
+        }
+        if let Some(favorite) = favorite {
+            entry.favorite = favorite;
+        }
+        let entry = entry.clone();
+
+        // Rows that say nothing are dropped instead of kept around
+        all.retain(|r| r.rating > 0 || r.favorite);
+        self.write_all(&all);
+
+        entry
+    }
+    fn get_all(&self) -> Vec<RatingEntry> {
+        let mut db = self.open_database_read();
+        db.records()
+            .filter_map(|r| r.ok())
+            .filter_map(|r| {
+                Some(RatingEntry {
+                    user: r.get(0)?.to_string(),
+                    song_path: r.get(1)?.to_string(),
+                    rating: r.get(2)?.parse().ok()?,
+                    favorite: r.get(3)? == "1",
+                })
+            })
+            .collect()
+    }
+    fn write_all(&mut self, all: &[RatingEntry]) {
+        {
+            let mut db = self.open_temp_database_write();
+            db.write_record(["user", "song_path", "rating", "favorite"]).unwrap();
+            for r in all {
+                let favorite = if r.favorite { "1" } else { "0" };
+                db.write_record([r.user.as_str(), r.song_path.as_str(), r.rating.to_string().as_str(), favorite]).unwrap();
+            }
+        }
+
+        self.copy_temp_database();
+    }
+    fn open_database_read(&self) -> csv::Reader<BufReader<File>> {
+        let rdr = csv::ReaderBuilder::new().from_reader(BufReader::new(File::open(&self.path).unwrap()));
+        rdr
+    }
+    fn open_temp_database_write(&mut self) -> csv::Writer<BufWriter<File>> {
+        let rdr = csv::WriterBuilder::new().from_writer(BufWriter::new(File::create(&format!("{}.tmp", self.path)).unwrap()));
+        rdr
+    }
+    fn copy_temp_database(&mut self) {
+        replace_with_temp(&self.path);
+    }
 }
 
 struct WhitelistDatabase {
@@ -68,6 +474,7 @@ struct WhitelistDatabase {
 }
 impl WhitelistDatabase {
     pub fn new(path: String) -> Self {
+        recover(&path, false);
         WhitelistDatabase { path }
     }
     pub fn is_allowed(&self, ip: &str) -> bool {
@@ -90,26 +497,69 @@ impl WhitelistDatabase {
         let mut rdr = csv::ReaderBuilder::new().from_reader(BufReader::new(File::open(&self.path).unwrap()));
         rdr
     }
-    fn open_database_write(&mut self) -> csv::Writer<BufWriter<File>> {
-        let mut rdr = csv::WriterBuilder::new().from_writer(BufWriter::new(File::create(&self.path).unwrap()));
-        rdr
-    }
     fn open_temp_database_write(&mut self) -> csv::Writer<BufWriter<File>> {
         let mut rdr = csv::WriterBuilder::new().from_writer(BufWriter::new(File::create(&format!("{}.tmp", self.path)).unwrap()));
         rdr
     }
     fn copy_temp_database(&mut self) {
-        fs::rename(&self.path, &format!("{}.bak", self.path)).unwrap();
-        fs::rename(&format!("{}.tmp", self.path), &self.path).unwrap();
+        replace_with_temp(&self.path);
     }
     
 }
 
+struct ShareTokenDatabase {
+    path: String,
+}
+impl ShareTokenDatabase {
+    pub fn new(path: String) -> Self {
+        if !Path::new(&path).exists() {
+            fs::write(&path, "token,user\n").unwrap();
+        }
+        recover(&path, true);
+        ShareTokenDatabase { path }
+    }
+    pub fn get_user(&self, token: &str) -> Option<String> {
+        let mut db = self.open_database_read();
+
+        for r in db.records() {
+            let Ok(r) = r else {
+                return None;
+            };
+            let csv_token: &str = r.get(0).unwrap();
+
+            if csv_token == token {
+                return Some(r.get(1).unwrap().to_string());
+            }
+        }
+
+        None
+    }
+    pub fn add_token(&mut self, token: &str, user: &str) {
+        let _lock = lock_store(&self.path);
+        let mut db = self.open_database_write();
+        db.write_record(&[token, user]).unwrap();
+        sync_append(db);
+    }
+    fn open_database_read(&self) -> csv::Reader<BufReader<File>> {
+        let rdr = csv::ReaderBuilder::new().from_reader(BufReader::new(File::open(&self.path).unwrap()));
+        rdr
+    }
+    fn open_database_write(&mut self) -> csv::Writer<BufWriter<File>> {
+        let rdr = csv::WriterBuilder::new().from_writer(BufWriter::new(File::options().append(true).open(&self.path).unwrap()));
+        rdr
+    }
+}
+
 struct PasswordDatabase {
     path: String,
 }
 impl PasswordDatabase {
     pub fn new(path: String) -> Self {
+        recover(&path, false);
+        // `add_user` used to create the file, now it rewrites it
+        if !Path::new(&path).exists() {
+            fs::write(&path, "user,password\n").unwrap();
+        }
         PasswordDatabase { path }
     }
     pub fn get_user(&self, user: &str, passowrd: &str) -> Option<String> {
@@ -130,114 +580,249 @@ impl PasswordDatabase {
         None
     }
     pub fn add_user(&mut self, user: &str, hashed_pw: &str) {
-        let mut db: csv::Writer<BufWriter<File>> = self.open_database_write();
-        db.write_record(&[
-            user, hashed_pw
-        ]).unwrap();
+        let _lock = lock_store(&self.path);
+        let mut all = self.get_all();
+        all.push((user.to_string(), hashed_pw.to_string(), String::new()));
+
+        self.write_all(&all);
     }
     pub(crate) fn update_user(&mut self, user: &str, base64_pass: &str) {
+        let _lock = lock_store(&self.path);
         let all = self.get_all();
-        let all = all.iter().filter(|(u, _)| u != &user);
+        let api_pass = all.iter().find(|(u, _, _)| u == user).map(|(_, _, a)| a.clone()).unwrap_or_default();
+        let mut all: Vec<_> = all.into_iter().filter(|(u, _, _)| u != user).collect();
+        all.push((user.to_string(), base64_pass.to_string(), api_pass));
+
+        self.write_all(&all);
+    }
+    /// The API password is stored in plain text, since Subsonic token auth
+    /// needs it to compute `md5(password + salt)`.
+    pub fn get_api_password(&self, user: &str) -> Option<String> {
+        self.get_all()
+            .into_iter()
+            .find(|(u, _, a)| u == user && !a.is_empty())
+            .map(|(_, _, a)| a)
+    }
+    pub(crate) fn set_api_password(&mut self, user: &str, api_pass: &str) -> bool {
+        let _lock = lock_store(&self.path);
+        let mut all = self.get_all();
+        let Some(row) = all.iter_mut().find(|(u, _, _)| u == user) else {
+            return false;
+        };
+        row.2 = api_pass.to_string();
+
+        self.write_all(&all);
+
+        true
+    }
+    fn write_all(&mut self, all: &[(String, String, String)]) {
+        let headers = self.open_database_read().headers().cloned().ok();
 
         {
             let mut db = self.open_temp_database_write();
-            for (user, pw) in all {
-                db.write_record(&[user, pw]).unwrap();
+            if let Some(headers) = headers {
+                db.write_record(&headers).unwrap();
+            }
+            for (user, pw, api_pw) in all {
+                if api_pw.is_empty() {
+                    db.write_record(&[user, pw]).unwrap();
+                } else {
+                    db.write_record(&[user, pw, api_pw]).unwrap();
+                }
             }
-            db.write_record(&[user, base64_pass]).unwrap();
         }
-        
+
         self.copy_temp_database();
     }
-    fn get_all(&self) -> Vec<(String, String)> {
+    fn get_all(&self) -> Vec<(String, String, String)> {
         let mut db = self.open_database_read();
-        db.records().filter_map(|r| r.ok()).map(|r| (r.get(0).unwrap().to_string(), r.get(1).unwrap().to_string())).collect()
+        db.records()
+            .filter_map(|r| r.ok())
+            .map(|r| {
+                (
+                    r.get(0).unwrap().to_string(),
+                    r.get(1).unwrap().to_string(),
+                    r.get(2).unwrap_or("").to_string(),
+                )
+            })
+            .collect()
     }
     fn open_database_read(&self) -> csv::Reader<BufReader<File>> {
-        let mut rdr = csv::ReaderBuilder::new().from_reader(BufReader::new(File::open(&self.path).unwrap()));
-        rdr
-    }
-    fn open_database_write(&mut self) -> csv::Writer<BufWriter<File>> {
-        let mut rdr = csv::WriterBuilder::new().from_writer(BufWriter::new(File::create(&self.path).unwrap()));
+        let mut rdr = csv::ReaderBuilder::new().flexible(true).from_reader(BufReader::new(File::open(&self.path).unwrap()));
         rdr
     }
     fn open_temp_database_write(&mut self) -> csv::Writer<BufWriter<File>> {
-        let mut rdr = csv::WriterBuilder::new().from_writer(BufWriter::new(File::create(&format!("{}.tmp", self.path)).unwrap()));
+        let mut rdr = csv::WriterBuilder::new().flexible(true).from_writer(BufWriter::new(File::create(&format!("{}.tmp", self.path)).unwrap()));
         rdr
     }
     fn copy_temp_database(&mut self) {
-        fs::rename(&self.path, &format!("{}.bak", self.path)).unwrap();
-        fs::rename(&format!("{}.tmp", self.path), &self.path).unwrap();
+        replace_with_temp(&self.path);
     }
 }
 
 struct SongDatabase {
     path: String,
+    index: Mutex<Option<Arc<SongIndex>>>,
 }
 
-impl SongDatabase {
-    pub fn new(path: String) -> Self {
-        SongDatabase { path }
+/// All songs, parsed once and kept until `songs.csv` changes. The list is
+/// also kept serialised, since `api/listSongs` sends all of it.
+pub(crate) struct SongIndex {
+    /// Modification time and size of the file the index was read from
+    stamp: Option<(SystemTime, u64)>,
+    pub songs: Vec<SongEntry<'static>>,
+    pub json: String,
+    pub etag: String,
+}
+
+impl SongIndex {
+    fn new(songs: Vec<SongEntry<'static>>, stamp: Option<(SystemTime, u64)>) -> Self {
+        let json = serde_json::to_string(&songs).unwrap();
+        let etag = crate::macros::get_etag(&json);
+
+        SongIndex {
+            stamp,
+            songs,
+            json,
+            etag,
+        }
     }
+}
 
-    pub fn get_song_by_title_and_artist(&self, title: &str, artist: &str) -> Option<SongEntry<'static>> {
-        let mut db = self.open_database_read();
-        for r in db.records() {
-            let Ok(r) = r else {
-                return None;
-            };
+fn split_list(value: &str) -> Vec<Cow<'_, str>> {
+    value.split(['\x1F', '␟']).map(Cow::Borrowed).collect()
+}
 
-            let (csv_title, csv_artist) = (r.get(0).unwrap(), r.get(1).unwrap());
-
-            let mut artists = csv_artist.split('␟');
-
-            if title == csv_title && artists.any(|a| a == artist) {
-                let album = r.get(2).unwrap();
-                let genres = r.get(3).unwrap();
-                let song_path = r.get(4).unwrap();
-                
-                return Some(SongEntry {
-                    title: Cow::Owned(csv_title.to_string()),
-                    artists: csv_artist.split('␟').into_iter().map(|a| Cow::Owned(a.to_string())).collect(),
-                    album: album.to_string().into(),
-                    genres: genres.split('␟').into_iter().map(|a| Cow::Owned(a.to_string())).collect(),
-                    song_path: song_path.to_string().into(),
-                });
-            }
+fn split_optional_list(value: Option<&str>) -> Vec<Cow<'_, str>> {
+    value.filter(|v| !v.is_empty()).map(split_list).unwrap_or_default()
+}
+
+fn get_optional(value: Option<&str>) -> Option<Cow<'_, str>> {
+    value.filter(|v| !v.is_empty()).map(Cow::Borrowed)
+}
+
+fn parse_optional<T: std::str::FromStr>(value: Option<&str>) -> Option<T> {
+    value.filter(|v| !v.is_empty()).and_then(|v| v.parse().ok())
+}
+
+fn format_optional(value: Option<f64>) -> String {
+    value.map(|v| format!("{v:.6}")).unwrap_or_default()
+}
+
+/// Columns, in order: title, artists, album, genres, song_path, track_gain,
+/// track_peak, album_gain, album_peak, recording_mbid, release_group_mbid,
+/// artist_mbids, source, track, duration_secs, content_hash. Older rows may stop after song_path.
+fn song_from_record(r: &csv::StringRecord) -> SongEntry<'_> {
+    SongEntry {
+        title: Cow::Borrowed(r.get(0).unwrap()),
+        artists: split_list(r.get(1).unwrap()),
+        album: Cow::Borrowed(r.get(2).unwrap()),
+        genres: split_list(r.get(3).unwrap()),
+        song_path: Cow::Borrowed(r.get(4).unwrap()),
+        track_gain: parse_optional(r.get(5)),
+        track_peak: parse_optional(r.get(6)),
+        album_gain: parse_optional(r.get(7)),
+        album_peak: parse_optional(r.get(8)),
+        recording_mbid: get_optional(r.get(9)),
+        release_group_mbid: get_optional(r.get(10)),
+        artist_mbids: split_optional_list(r.get(11)),
+        source: get_optional(r.get(12)),
+        track: r.get(13).and_then(|t| t.parse().ok()),
+        duration_secs: parse_optional(r.get(14)),
+        content_hash: get_optional(r.get(15)),
+    }
+}
+
+fn song_to_record(song: &SongEntry) -> Vec<String> {
+    vec![
+        song.title.to_string(),
+        song.artists.join("\x1F"),
+        song.album.to_string(),
+        song.genres.join("\x1F"),
+        song.song_path.to_string(),
+        format_optional(song.track_gain),
+        format_optional(song.track_peak),
+        format_optional(song.album_gain),
+        format_optional(song.album_peak),
+        song.recording_mbid.as_deref().unwrap_or_default().to_string(),
+        song.release_group_mbid.as_deref().unwrap_or_default().to_string(),
+        song.artist_mbids.join("\x1F"),
+        song.source.as_deref().unwrap_or_default().to_string(),
+        song.track.map(|t| t.to_string()).unwrap_or_default(),
+        song.duration_secs.map(|d| d.to_string()).unwrap_or_default(),
+        song.content_hash.as_deref().unwrap_or_default().to_string(),
+    ]
+}
+
+impl SongEntry<'_> {
+    pub fn into_owned(self) -> SongEntry<'static> {
+        SongEntry {
+            title: Cow::Owned(self.title.into_owned()),
+            album: Cow::Owned(self.album.into_owned()),
+            artists: self.artists.into_iter().map(|a| Cow::Owned(a.into_owned())).collect(),
+            genres: self.genres.into_iter().map(|g| Cow::Owned(g.into_owned())).collect(),
+            song_path: Cow::Owned(self.song_path.into_owned()),
+            track_gain: self.track_gain,
+            track_peak: self.track_peak,
+            album_gain: self.album_gain,
+            album_peak: self.album_peak,
+            recording_mbid: self.recording_mbid.map(|m| Cow::Owned(m.into_owned())),
+            release_group_mbid: self.release_group_mbid.map(|m| Cow::Owned(m.into_owned())),
+            artist_mbids: self.artist_mbids.into_iter().map(|m| Cow::Owned(m.into_owned())).collect(),
+            source: self.source.map(|s| Cow::Owned(s.into_owned())),
+            track: self.track,
+            duration_secs: self.duration_secs,
+            content_hash: self.content_hash.map(|h| Cow::Owned(h.into_owned())),
         }
+    }
+}
 
-        None
+impl SongDatabase {
+    pub fn new(path: String) -> Self {
+        recover(&path, true);
+        SongDatabase { path, index: Mutex::new(None) }
     }
 
-    pub fn get_all_json(&self) -> String {
-        let mut db = self.open_database_read();
-        let mut json = String::from("[");
-        for r in db.records() {
-            let Ok(r) = r else {
-                break;
-            };
+    fn get_stamp(&self) -> Option<(SystemTime, u64)> {
+        let metadata = fs::metadata(&self.path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    /// The current index, read again if the file was changed by someone
+    /// else, like the CLI or a text editor.
+    pub fn index(&self) -> Arc<SongIndex> {
+        let mut index = self.index.lock().unwrap();
+        let stamp = self.get_stamp();
 
-            let (csv_title, csv_artist) = (r.get(0).unwrap(), r.get(1).unwrap());
-            let album = r.get(2).unwrap();
-            let genres = r.get(3).unwrap();
-            let song_path = r.get(4).unwrap();
-                
-            json.push_str(&serde_json::to_string(&SongEntry {
-                title: Cow::Borrowed(csv_title),
-                artists: csv_artist.split('␟').into_iter().map(|a| Cow::Borrowed(a)).collect(),
-                album: album.into(),
-                genres: genres.split('␟').into_iter().map(|a| Cow::Borrowed(a)).collect(),
-                song_path: song_path.into(),
-            }).unwrap());
-            json.push_str(",");
+        if let Some(index) = index.as_ref().filter(|i| i.stamp.is_some() && i.stamp == stamp) {
+            return index.clone();
         }
 
-        json.replace_range(json.len() - 1.., "]");
-        
-        json
+        log::debug!("Loading {}", self.path);
+        let loaded = Arc::new(SongIndex::new(self.read_all(), stamp));
+        *index = Some(loaded.clone());
+
+        loaded
+    }
+
+    /// Replaces the index after a write, without reading the file back.
+    fn set_index(&self, songs: Vec<SongEntry<'static>>) {
+        *self.index.lock().unwrap() = Some(Arc::new(SongIndex::new(songs, self.get_stamp())));
+    }
+
+    pub fn get_song_by_title_and_artist(&self, title: &str, artist: &str) -> Option<SongEntry<'static>> {
+        self.index()
+            .songs
+            .iter()
+            .find(|song| title == song.title && song.artists.iter().any(|a| a == artist))
+            .cloned()
     }
 
     pub fn get_all(&self) -> Vec<SongEntry<'static>> {
+        self.index().songs.clone()
+    }
+
+    fn read_all(&self) -> Vec<SongEntry<'static>> {
         let mut db = self.open_database_read();
         let mut entries = Vec::new();
         for r in db.records() {
@@ -245,41 +830,202 @@ impl SongDatabase {
                 break;
             };
 
-            let (csv_title, csv_artist) = (r.get(0).unwrap(), r.get(1).unwrap());
-            let album = r.get(2).unwrap();
-            let genres = r.get(3).unwrap();
-            let song_path = r.get(4).unwrap();
-                
-            entries.push(SongEntry {
-                title: csv_title.to_string().into(),
-                artists: csv_artist.split('\x1F').into_iter().map(|a| a.to_string().into()).collect(),
-                album: album.to_string().into(),
-                genres: genres.split('\x1F').into_iter().map(|a| a.to_string().into()).collect(),
-                song_path: song_path.to_string().into(),
-            });
+            entries.push(song_from_record(&r).into_owned());
         }
 
         entries
     }
 
     pub fn add_song(&mut self, song: &SongEntry) {
+        let _lock = lock_store(&self.path);
+        let mut songs = self.get_all();
+
         let mut db = self.open_database_write();
-        db.write_record(&[
-            song.title.as_ref(),
-            &song.artists.join("\x1F"),
-            song.album.as_ref(),
-            &song.genres.join("\x1F"),
-            &song.song_path,
-        ]).unwrap();
+        db.write_record(&song_to_record(song)).unwrap();
+        sync_append(db);
+
+        songs.push(song.clone().into_owned());
+        self.set_index(songs);
+    }
+
+    /// Replaces the rows with the same `song_path` as any of `songs`.
+    pub fn update_songs(&mut self, songs: &[SongEntry]) {
+        let _lock = lock_store(&self.path);
+        let all: Vec<_> = self
+            .get_all()
+            .into_iter()
+            .map(|song| songs.iter().find(|s| s.song_path == song.song_path).cloned().unwrap_or(song))
+            .collect();
+
+        self.rewrite(&all);
+    }
+
+    pub fn update_song(&mut self, song_path: &str, update: impl FnOnce(&mut SongEntry<'static>)) -> Option<SongEntry<'static>> {
+        let _lock = lock_store(&self.path);
+        let mut all = self.get_all();
+        let song = all.iter_mut().find(|s| s.song_path == song_path)?;
+        update(song);
+        let song = song.clone();
+
+        self.rewrite(&all);
+        Some(song)
+    }
+
+    pub fn retain(&mut self, keep: impl FnMut(&SongEntry<'static>) -> bool) {
+        let _lock = lock_store(&self.path);
+        let mut all = self.get_all();
+        all.retain(keep);
+        self.rewrite(&all);
+    }
+
+    fn rewrite(&mut self, all: &[SongEntry]) {
+        let headers = self.open_database_read().headers().cloned().ok();
+
+        {
+            let mut db = self.open_temp_database_write();
+            if let Some(headers) = headers {
+                db.write_record(&headers).unwrap();
+            }
+            for song in all {
+                db.write_record(&song_to_record(song)).unwrap();
+            }
+        }
+
+        self.copy_temp_database();
+        self.set_index(all.iter().map(|s| s.clone().into_owned()).collect());
     }
 
     fn open_database_read(&self) -> csv::Reader<BufReader<File>> {
-        let mut rdr = csv::ReaderBuilder::new().delimiter(b'\x1D').from_reader(BufReader::new(File::open(&self.path).unwrap()));
+        let rdr = csv::ReaderBuilder::new().delimiter(b'\x1D').flexible(true).from_reader(BufReader::new(File::open(&self.path).unwrap()));
         rdr
     }
 
     fn open_database_write(&self) -> csv::Writer<BufWriter<File>> {
-        let mut rdr = csv::WriterBuilder::new().delimiter(b'\x1D').from_writer(BufWriter::new(File::options().append(true).open(&self.path).unwrap()));
+        let rdr = csv::WriterBuilder::new().delimiter(b'\x1D').from_writer(BufWriter::new(File::options().append(true).open(&self.path).unwrap()));
         rdr
     }
-}
\ No newline at end of file
+
+    fn open_temp_database_write(&mut self) -> csv::Writer<BufWriter<File>> {
+        let rdr = csv::WriterBuilder::new().delimiter(b'\x1D').flexible(true).from_writer(BufWriter::new(File::create(&format!("{}.tmp", self.path)).unwrap()));
+        rdr
+    }
+
+    fn copy_temp_database(&mut self) {
+        replace_with_temp(&self.path);
+    }
+}
+
+/// Held while a store is written, so the CLI and a running server can't
+/// interleave their writes. The lock is released when the file is dropped.
+fn lock_store(path: &str) -> File {
+    let lock = File::options().create(true).write(true).open(format!("{path}.lock")).unwrap();
+    lock.lock_exclusive().unwrap();
+    lock
+}
+
+/// Flushes an appended row all the way to disk.
+fn sync_append(db: csv::Writer<BufWriter<File>>) {
+    let file = db.into_inner().unwrap().into_inner().unwrap();
+    file.sync_all().unwrap();
+}
+
+fn sync_parent_dir(path: &str) {
+    let parent = Path::new(path).parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    if let Ok(dir) = File::open(parent) {
+        let _ = dir.sync_all();
+    }
+}
+
+/// Swaps in the `.tmp` file written by `open_temp_database_write`. The old
+/// version is copied to `.bak` first and the new one is on disk before the
+/// rename, so after a crash the store is either the old or the new version.
+fn replace_with_temp(path: &str) {
+    let tmp = format!("{path}.tmp");
+    let bak = format!("{path}.bak");
+
+    File::open(&tmp).unwrap().sync_all().unwrap();
+
+    if Path::new(path).exists() {
+        fs::copy(path, &bak).unwrap();
+        File::open(&bak).unwrap().sync_all().unwrap();
+    }
+
+    fs::rename(&tmp, path).unwrap();
+    sync_parent_dir(path);
+}
+
+/// Copies a store while no one writes to it, so the copy is one consistent
+/// version.
+pub(crate) fn copy_store_file(path: &str, to: &Path) -> std::io::Result<()> {
+    let _lock = lock_store(path);
+
+    fs::copy(path, to)?;
+
+    Ok(())
+}
+
+/// Replaces a whole store with another file, the same way rewrites do.
+pub(crate) fn replace_store_file(path: &str, new_file: &Path) -> std::io::Result<()> {
+    let _lock = lock_store(path);
+
+    fs::copy(new_file, format!("{path}.tmp"))?;
+    replace_with_temp(path);
+
+    Ok(())
+}
+
+/// Cleans up after a write that was cut short: drops a leftover `.tmp`,
+/// restores the store from `.bak` if it is gone, and for stores that are
+/// appended to, sets a half-written last row aside in `.partial`.
+fn recover(path: &str, appended: bool) {
+    let _lock = lock_store(path);
+
+    let tmp = format!("{path}.tmp");
+    if Path::new(&tmp).exists() {
+        log::warn!("Removing unfinished write {tmp}");
+        fs::remove_file(&tmp).unwrap();
+    }
+
+    let bak = format!("{path}.bak");
+    if !Path::new(path).exists() && Path::new(&bak).exists() {
+        log::warn!("{path} is missing, restoring it from {bak}");
+        fs::copy(&bak, path).unwrap();
+        File::open(path).unwrap().sync_all().unwrap();
+    }
+
+    if !appended {
+        return;
+    }
+    let Ok(content) = fs::read(path) else {
+        return;
+    };
+    if content.is_empty() || content.ends_with(b"\n") {
+        return;
+    }
+
+    let end = content.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    log::warn!("Moving a partially written row of {path} to {path}.partial");
+    fs::write(format!("{path}.partial"), &content[end..]).unwrap();
+    let file = File::options().write(true).open(path).unwrap();
+    file.set_len(end as u64).unwrap();
+    file.sync_all().unwrap();
+}
+
+/// A database with empty stores in `dir`, for tests.
+#[cfg(test)]
+pub(crate) fn open_test_database(dir: &Path) -> Database {
+    let path = |name: &str| dir.join(name).display().to_string();
+
+    fs::write(path("songs.csv"), "title\x1Dartists\x1Dalbum\x1Dgenres\x1Dsong_path\n").unwrap();
+    fs::write(path("whitelist.csv"), "ip\n").unwrap();
+
+    Database::open(
+        path("songs.csv"),
+        path("users.csv"),
+        path("whitelist.csv"),
+        path("tokens.csv"),
+        path("jobs.csv"),
+        path("plays.csv"),
+        path("ratings.csv"),
+    )
+}
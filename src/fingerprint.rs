@@ -0,0 +1,231 @@
+//! Identifies untagged songs by their sound: a Chromaprint fingerprint is
+//! looked up with an AcoustID-compatible service, which returns MusicBrainz
+//! recording IDs.
+//!
+//! The service is configured with `JUKBX_ACOUSTID_URL` (defaults to the
+//! public AcoustID API) and `JUKBX_ACOUSTID_KEY`.
+
+use std::env;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rusty_chromaprint::{Configuration, Fingerprinter};
+use serde::Deserialize;
+
+use crate::audio;
+
+/// AcoustID only looks at the start of a song
+const MAX_SECONDS: u64 = 120;
+/// Algorithm ID of `Configuration::preset_test2`, the Chromaprint default
+const ALGORITHM_TEST2: u8 = 1;
+
+pub(crate) struct Fingerprint {
+    /// Compressed and base64 encoded, as `fpcalc` prints it
+    pub fingerprint: String,
+    pub duration_secs: u64,
+}
+
+pub(crate) fn compute(data: Vec<u8>) -> anyhow::Result<Fingerprint> {
+    let config = Configuration::preset_test2();
+    let mut printer = Fingerprinter::new(&config);
+    let mut started = false;
+    let mut total_frames = 0u64;
+    let mut sample_rate = 0u32;
+    let mut samples_i16 = Vec::new();
+    let mut error = None;
+
+    audio::decode_bytes(data, |samples, channels, rate| {
+        if error.is_some() {
+            return;
+        }
+
+        if !started {
+            if let Err(e) = printer.start(rate, channels as u32) {
+                error = Some(anyhow::anyhow!("Failed to start fingerprinter: {e:?}"));
+                return;
+            }
+            started = true;
+            sample_rate = rate;
+        }
+
+        // Keep counting frames past the limit for the duration
+        let frames = (samples.len() / channels) as u64;
+        let remaining = (MAX_SECONDS * sample_rate as u64).saturating_sub(total_frames);
+        total_frames += frames;
+
+        if remaining > 0 {
+            let take = frames.min(remaining) as usize * channels;
+            samples_i16.clear();
+            samples_i16.extend(
+                samples[..take]
+                    .iter()
+                    .map(|s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16),
+            );
+            printer.consume(&samples_i16);
+        }
+    })?;
+
+    if let Some(e) = error {
+        return Err(e);
+    }
+    if !started {
+        return Err(anyhow::anyhow!("No audio decoded"));
+    }
+
+    printer.finish();
+
+    Ok(Fingerprint {
+        fingerprint: compress(printer.fingerprint(), ALGORITHM_TEST2),
+        duration_secs: total_frames / sample_rate as u64,
+    })
+}
+
+/// Packs values of `width` bits, least significant bit first.
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    acc: u32,
+    bits: u32,
+}
+
+impl BitWriter<'_> {
+    fn write(&mut self, value: u8, width: u32) {
+        self.acc |= (value as u32) << self.bits;
+        self.bits += width;
+        while self.bits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.bits > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.acc = 0;
+        self.bits = 0;
+    }
+}
+
+/// Chromaprint's compressed fingerprint format: each subfingerprint is
+/// XORed with the previous one and stored as the gaps between its set bits,
+/// in 3-bit values with 5-bit overflow values after them.
+fn compress(fingerprint: &[u32], algorithm: u8) -> String {
+    let mut gaps = Vec::new();
+    let mut prev = 0u32;
+    for &x in fingerprint {
+        let mut value = x ^ prev;
+        prev = x;
+
+        let mut bit = 1u8;
+        let mut last_bit = 0u8;
+        while value != 0 {
+            if value & 1 != 0 {
+                gaps.push(bit - last_bit);
+                last_bit = bit;
+            }
+            value >>= 1;
+            bit += 1;
+        }
+        gaps.push(0);
+    }
+
+    let len = fingerprint.len();
+    let mut out = vec![algorithm, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+
+    let mut writer = BitWriter {
+        out: &mut out,
+        acc: 0,
+        bits: 0,
+    };
+    for &gap in &gaps {
+        writer.write(gap.min(7), 3);
+    }
+    writer.flush();
+    for &gap in gaps.iter().filter(|&&g| g >= 7) {
+        writer.write(gap - 7, 5);
+    }
+    writer.flush();
+
+    URL_SAFE_NO_PAD.encode(out)
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    status: String,
+    #[serde(default)]
+    results: Vec<LookupResult>,
+}
+
+#[derive(Deserialize)]
+struct LookupResult {
+    score: f64,
+    #[serde(default)]
+    recordings: Vec<LookupRecording>,
+}
+
+#[derive(Deserialize)]
+struct LookupRecording {
+    id: String,
+}
+
+/// Returns the MusicBrainz recording IDs matching a fingerprint with their
+/// AcoustID score from 0 to 1, best match first.
+pub(crate) fn lookup(fingerprint: &Fingerprint) -> anyhow::Result<Vec<(String, f64)>> {
+    let base_url =
+        env::var("JUKBX_ACOUSTID_URL").unwrap_or("https://api.acoustid.org".to_string());
+    let key = env::var("JUKBX_ACOUSTID_KEY")
+        .map_err(|_| anyhow::anyhow!("JUKBX_ACOUSTID_KEY is not set"))?;
+
+    let duration = fingerprint.duration_secs.to_string();
+    let response: LookupResponse = ureq::post(&format!("{base_url}/v2/lookup"))
+        .send_form(&[
+            ("client", key.as_str()),
+            ("meta", "recordingids"),
+            ("duration", duration.as_str()),
+            ("fingerprint", fingerprint.fingerprint.as_str()),
+        ])?
+        .into_json()?;
+
+    if response.status != "ok" {
+        return Err(anyhow::anyhow!("AcoustID lookup failed: {}", response.status));
+    }
+
+    let mut results = response.results;
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    let mut ids: Vec<(String, f64)> = Vec::new();
+    for result in results {
+        for recording in result.recordings {
+            if !ids.iter().any(|(id, _)| *id == recording.id) {
+                ids.push((recording.id, result.score));
+            }
+        }
+    }
+
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressed_bytes(fingerprint: &[u32]) -> Vec<u8> {
+        URL_SAFE_NO_PAD.decode(compress(fingerprint, 0)).unwrap()
+    }
+
+    // Expected values from Chromaprint's own compressor tests
+    #[test]
+    fn compress_matches_chromaprint() {
+        assert_eq!(compressed_bytes(&[1]), [0, 0, 0, 1, 1]);
+        assert_eq!(compressed_bytes(&[7]), [0, 0, 0, 1, 73, 0]);
+        assert_eq!(compressed_bytes(&[1 << 6]), [0, 0, 0, 1, 7, 0]);
+        assert_eq!(compressed_bytes(&[1 << 8]), [0, 0, 0, 1, 7, 2]);
+        assert_eq!(compressed_bytes(&[1, 0]), [0, 0, 0, 2, 65, 0]);
+        assert_eq!(compressed_bytes(&[1, 1]), [0, 0, 0, 2, 1, 0]);
+    }
+
+    #[test]
+    fn compress_writes_algorithm_and_base64() {
+        assert_eq!(compress(&[1], ALGORITHM_TEST2), "AQAAAQE");
+    }
+}
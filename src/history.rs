@@ -0,0 +1,195 @@
+//! Play history. A play is recorded when `/data/` serves a song from its
+//! start, when a player calls `api/scrobble`, or on a Subsonic `scrobble`.
+//!
+//! With `JUKBX_LISTENBRAINZ_TOKEN` set, plays are also submitted to
+//! ListenBrainz, or to whatever `JUKBX_LISTENBRAINZ_URL` points at.
+
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tiny_http::{Request, Response, ResponseBox};
+
+use crate::{
+    data::{Database, PlayEntry, SongEntry},
+    jobs::{self, JobKind},
+    macros::get_query_param,
+};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 1000;
+
+pub(crate) fn record_play(db: &Database, song_path: &str, listener: &str, source: &str) {
+    let play = PlayEntry {
+        song_path: song_path.to_string(),
+        listener: listener.to_string(),
+        played_at: jobs::now(),
+        source: source.to_string(),
+    };
+    db.add_play(&play);
+
+    // The job is created at the time of the play, which is what gets submitted
+    if env::var("JUKBX_LISTENBRAINZ_TOKEN").is_ok() {
+        db.add_job(JobKind::Listen, song_path, play.played_at);
+    }
+}
+
+#[derive(Deserialize)]
+struct ScrobbleRequest {
+    song_path: String,
+}
+
+pub(crate) fn scrobble(db: &Database, req: &mut Request) -> ResponseBox {
+    let user = crate::try_auth!(db, req);
+    let r: ScrobbleRequest = crate::try_json!(req);
+
+    if db.get_song_by_path(&r.song_path).is_none() {
+        return Response::from_string("").with_status_code(404).boxed();
+    }
+
+    record_play(db, &r.song_path, &user, "scrobble");
+
+    Response::from_string("{}").with_status_code(200).boxed()
+}
+
+fn get_limit(url: &str) -> usize {
+    get_query_param(url, "limit")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(DEFAULT_LIMIT)
+        .min(MAX_LIMIT)
+}
+
+/// Plays, newest first, of everyone or only `?listener=`.
+fn get_plays(db: &Database, url: &str) -> Vec<PlayEntry> {
+    let listener = get_query_param(url, "listener");
+
+    let mut plays: Vec<PlayEntry> = db
+        .get_plays()
+        .into_iter()
+        .filter(|p| listener.as_ref().map_or(true, |l| *l == p.listener))
+        .collect();
+    plays.reverse();
+
+    plays
+}
+
+/// Serves `api/history`, the latest play events.
+pub(crate) fn history(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_auth!(db, req);
+
+    let url = req.url();
+    let mut plays = get_plays(db, url);
+    plays.truncate(get_limit(url));
+
+    crate::to_json!(&plays)
+}
+
+#[derive(Serialize)]
+struct PlayedSong {
+    song: SongEntry<'static>,
+    play_count: usize,
+    last_played_at: u64,
+}
+
+fn to_played_songs(db: &Database, plays: &[PlayEntry]) -> Vec<PlayedSong> {
+    let mut counts: HashMap<&str, (usize, u64)> = HashMap::new();
+    for play in plays {
+        let entry = counts.entry(&play.song_path).or_default();
+        entry.0 += 1;
+        entry.1 = entry.1.max(play.played_at);
+    }
+
+    db.get_all_songs()
+        .into_iter()
+        .filter_map(|song| {
+            let (play_count, last_played_at) = *counts.get(&song.song_path[..])?;
+            Some(PlayedSong {
+                song,
+                play_count,
+                last_played_at,
+            })
+        })
+        .collect()
+}
+
+/// Serves `api/mostPlayed`, optionally only counting the last `?days=`.
+pub(crate) fn most_played(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_auth!(db, req);
+
+    let url = req.url();
+    let mut plays = get_plays(db, url);
+    if let Some(days) = get_query_param(url, "days").and_then(|d| d.parse::<u64>().ok()) {
+        let since = jobs::now().saturating_sub(days * 60 * 60 * 24);
+        plays.retain(|p| p.played_at >= since);
+    }
+
+    let mut songs = to_played_songs(db, &plays);
+    songs.sort_by(|a, b| {
+        b.play_count
+            .cmp(&a.play_count)
+            .then(b.last_played_at.cmp(&a.last_played_at))
+    });
+    songs.truncate(get_limit(url));
+
+    crate::to_json!(&songs)
+}
+
+/// Serves `api/recentlyPlayed`, each song once by its latest play.
+pub(crate) fn recently_played(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_auth!(db, req);
+
+    let url = req.url();
+    let plays = get_plays(db, url);
+
+    let mut seen = HashSet::new();
+    let recent: Vec<PlayEntry> = plays
+        .into_iter()
+        .filter(|p| seen.insert(p.song_path.clone()))
+        .take(get_limit(url))
+        .collect();
+
+    let mut songs = to_played_songs(db, &recent);
+    songs.sort_by(|a, b| b.last_played_at.cmp(&a.last_played_at));
+
+    crate::to_json!(&songs)
+}
+
+/// Submits one listen. Called from the job queue, which retries on failure.
+pub(crate) fn submit_listen(song: &SongEntry, listened_at: u64) -> anyhow::Result<()> {
+    let token = env::var("JUKBX_LISTENBRAINZ_TOKEN")
+        .map_err(|_| anyhow::anyhow!("JUKBX_LISTENBRAINZ_TOKEN is not set"))?;
+    let base_url = env::var("JUKBX_LISTENBRAINZ_URL")
+        .unwrap_or("https://api.listenbrainz.org".to_string());
+
+    let mut additional_info = json!({ "submission_client": "jukbx" });
+    if let Some(mbid) = &song.recording_mbid {
+        additional_info["recording_mbid"] = mbid.to_string().into();
+    }
+    if let Some(mbid) = &song.release_group_mbid {
+        additional_info["release_group_mbid"] = mbid.to_string().into();
+    }
+    if !song.artist_mbids.is_empty() {
+        additional_info["artist_mbids"] = song.artist_mbids.iter().map(|m| m.to_string()).collect();
+    }
+
+    let mut track_metadata = json!({
+        "artist_name": song.artists.join(", "),
+        "track_name": song.title,
+        "additional_info": additional_info,
+    });
+    if !song.album.is_empty() {
+        track_metadata["release_name"] = song.album.to_string().into();
+    }
+
+    ureq::post(&format!("{base_url}/1/submit-listens"))
+        .set("Authorization", &format!("Token {token}"))
+        .send_json(json!({
+            "listen_type": "single",
+            "payload": [{ "listened_at": listened_at, "track_metadata": track_metadata }],
+        }))?;
+
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! Checks that `songs.csv` and `./songs/` agree with each other.
+//!
+//! Without `repair` nothing is changed. With it, rows of missing files and
+//! duplicate rows are dropped, and orphan files are moved to `./orphans/`
+//! rather than deleted. Unreadable files are only reported. A repair from
+//! `api/verify` runs as a job, like a scan.
+
+use std::{collections::HashSet, fs, path::Path};
+
+use lofty::probe::Probe;
+use serde::{Deserialize, Serialize};
+use tiny_http::{Request, Response, ResponseBox};
+
+use crate::{data::Database, jobs::JobKind};
+
+const ORPHAN_DIR: &str = "./orphans";
+
+#[derive(Default, Serialize)]
+pub(crate) struct IntegrityReport {
+    /// Rows whose file is gone
+    pub missing: Vec<String>,
+    /// Files in `./songs/` no row refers to, sidecars of known songs aside
+    pub orphans: Vec<String>,
+    /// Files lofty can't read
+    pub unreadable: Vec<String>,
+    /// `song_path`s with more than one row
+    pub duplicates: Vec<String>,
+    pub repaired: bool,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty()
+            && self.orphans.is_empty()
+            && self.unreadable.is_empty()
+            && self.duplicates.is_empty()
+    }
+}
+
+pub(crate) fn verify(db: &Database, repair: bool) -> IntegrityReport {
+    let mut report = IntegrityReport::default();
+    let songs = db.get_all_songs();
+
+    let mut seen = HashSet::new();
+    for song in &songs {
+        let song_path = song.song_path.to_string();
+        if !seen.insert(song_path.clone()) {
+            if !report.duplicates.contains(&song_path) {
+                report.duplicates.push(song_path);
+            }
+            continue;
+        }
+
+        // Follows links, so songs scanned in place whose source is gone count
+        let path = format!("./songs/{song_path}");
+        if !Path::new(&path).is_file() {
+            report.missing.push(song_path);
+        } else if let Err(e) = Probe::open(&path).and_then(|p| p.read()) {
+            log::warn!("Failed to read {path}: {e:?}");
+            report.unreadable.push(song_path);
+        }
+    }
+
+    if let Ok(dir) = fs::read_dir("./songs") {
+        for entry in dir.filter_map(|e| e.ok()) {
+            if entry.file_type().map_or(true, |t| t.is_dir()) {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            let is_known = seen.contains(&name)
+                || seen.iter().any(|s| name.starts_with(&format!("{s}.")));
+            if !is_known {
+                report.orphans.push(name);
+            }
+        }
+    }
+    report.orphans.sort();
+
+    if repair && !report.is_clean() {
+        repair_library(db, &report);
+        report.repaired = true;
+    }
+
+    report
+}
+
+fn repair_library(db: &Database, report: &IntegrityReport) {
+    if !report.missing.is_empty() || !report.duplicates.is_empty() {
+        let mut seen = HashSet::new();
+        db.retain_songs(|s| {
+            !report.missing.iter().any(|m| *m == s.song_path) && seen.insert(s.song_path.to_string())
+        });
+        log::info!(
+            "Removed {} missing and {} duplicate rows",
+            report.missing.len(),
+            report.duplicates.len()
+        );
+    }
+
+    if !report.orphans.is_empty() {
+        if let Err(e) = fs::create_dir_all(ORPHAN_DIR) {
+            log::error!("Failed to create {ORPHAN_DIR}: {e:?}");
+            return;
+        }
+
+        for orphan in &report.orphans {
+            let from = format!("./songs/{orphan}");
+            let to = format!("{ORPHAN_DIR}/{orphan}");
+            match fs::rename(&from, &to) {
+                Ok(()) => log::info!("Moved {from} to {to}"),
+                Err(e) => log::warn!("Failed to move {from}: {e:?}"),
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct VerifyRequest {
+    #[serde(default)]
+    repair: bool,
+}
+
+#[derive(Serialize)]
+struct RepairResponse {
+    job: u64,
+}
+
+/// Runs the check for admins as a dry run, answering with the report. With
+/// `repair` set it queues a repair instead and returns the job, which lists
+/// in `api/jobs` with the report as its `result` once it is done.
+pub(crate) fn verify_library(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_admin!(db, req);
+    let r: VerifyRequest = crate::try_json!(req);
+
+    if r.repair {
+        let job = db.add_job(JobKind::Repair, "", crate::jobs::now());
+        return crate::to_json!(&RepairResponse { job: job.id });
+    }
+
+    let report = verify(db, false);
+
+    crate::to_json!(&report)
+}
@@ -0,0 +1,399 @@
+//! Slow work done after a song is added, so uploads can return right away.
+//! Jobs are kept in `jobs.csv` and picked up by a single worker thread,
+//! failed jobs are retried with exponential backoff.
+
+use std::{
+    borrow::Cow,
+    fs,
+    io::Read,
+    path::Path,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tiny_http::{Request, Response, ResponseBox};
+
+use crate::{
+    data::{Database, JobEntry},
+    metadata,
+    scanner::ImportMode,
+    song::get_sidecar_path,
+    transcode::{self, TranscodeFormat},
+};
+
+const MAX_ATTEMPTS: u32 = 5;
+const BASE_BACKOFF_SECS: u64 = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Finished jobs are dropped from the queue after a week
+const KEEP_FINISHED_SECS: u64 = 60 * 60 * 24 * 7;
+/// Lowest candidate score an untagged recording is matched with
+const ENRICH_MIN_SCORE: u32 = 90;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum JobKind {
+    Enrich,
+    /// Track gain and peak only, album gain comes from `loudness-scan`
+    Loudness,
+    Waveform,
+    Transcode,
+    Cover,
+    /// Hashes the audio, to find the song again on a library import
+    Hash,
+    /// Submits the play at `created_at` to ListenBrainz
+    Listen,
+    /// Reads the duration of an uploaded song
+    Duration,
+    /// Writes the metadata of an uploaded song to its file
+    Tags,
+    /// Keeps the lyrics embedded in an uploaded song
+    Lyrics,
+    /// Scans the music directory in `song_path`, copying new songs
+    Scan,
+    /// Scans the music directory in `song_path`, linking new songs
+    #[serde(rename = "scan-in-place")]
+    ScanInPlace,
+    /// Checks the library and repairs what it can, see `integrity`
+    Repair,
+}
+
+impl JobKind {
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "enrich" => Some(JobKind::Enrich),
+            "loudness" => Some(JobKind::Loudness),
+            "waveform" => Some(JobKind::Waveform),
+            "transcode" => Some(JobKind::Transcode),
+            "cover" => Some(JobKind::Cover),
+            "hash" => Some(JobKind::Hash),
+            "listen" => Some(JobKind::Listen),
+            "duration" => Some(JobKind::Duration),
+            "tags" => Some(JobKind::Tags),
+            "lyrics" => Some(JobKind::Lyrics),
+            "scan" => Some(JobKind::Scan),
+            "scan-in-place" => Some(JobKind::ScanInPlace),
+            "repair" => Some(JobKind::Repair),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::Enrich => "enrich",
+            JobKind::Loudness => "loudness",
+            JobKind::Waveform => "waveform",
+            JobKind::Transcode => "transcode",
+            JobKind::Cover => "cover",
+            JobKind::Hash => "hash",
+            JobKind::Listen => "listen",
+            JobKind::Duration => "duration",
+            JobKind::Tags => "tags",
+            JobKind::Lyrics => "lyrics",
+            JobKind::Scan => "scan",
+            JobKind::ScanInPlace => "scan-in-place",
+            JobKind::Repair => "repair",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "pending" => Some(JobStatus::Pending),
+            "running" => Some(JobStatus::Running),
+            "done" => Some(JobStatus::Done),
+            "failed" => Some(JobStatus::Failed),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Queues the processing of a newly added song. Enrichment goes first so the
+/// cover fetch can use the release group it finds.
+pub(crate) fn enqueue_song(db: &Database, song_path: &str) -> Vec<u64> {
+    let now = now();
+    [
+        JobKind::Enrich,
+        JobKind::Loudness,
+        JobKind::Waveform,
+        JobKind::Cover,
+        JobKind::Transcode,
+        JobKind::Hash,
+    ]
+    .into_iter()
+    .map(|kind| db.add_job(kind, song_path, now).id)
+    .collect()
+}
+
+/// Starts the worker thread. Jobs left running by a previous process are
+/// queued again, and songs from before audio hashes get theirs.
+pub(crate) fn start(db: Database) {
+    let now = now();
+    db.update_jobs(|jobs| {
+        jobs.retain(|j| {
+            !matches!(j.status, JobStatus::Done | JobStatus::Failed)
+                || now.saturating_sub(j.created_at) < KEEP_FINISHED_SECS
+        });
+        for job in jobs.iter_mut().filter(|j| j.status == JobStatus::Running) {
+            job.status = JobStatus::Pending;
+        }
+    });
+
+    let jobs = db.get_jobs();
+    for song in db.get_all_songs().iter().filter(|s| s.content_hash.is_none()) {
+        let is_queued = jobs.iter().any(|j| {
+            j.kind == JobKind::Hash && j.song_path == song.song_path && j.status != JobStatus::Done
+        });
+        if !is_queued {
+            db.add_job(JobKind::Hash, &song.song_path, now);
+        }
+    }
+
+    thread::spawn(move || loop {
+        if !run_next(&db) {
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+}
+
+/// Runs the oldest job that is due, returning false if there was none.
+fn run_next(db: &Database) -> bool {
+    let now = now();
+    // Polling only reads, the queue is rewritten once there is a job to claim
+    if !db.get_jobs().iter().any(|j| j.is_due(now)) {
+        return false;
+    }
+    let Some(job) = db.claim_job(now) else {
+        return false;
+    };
+
+    log::debug!("Running {} job {} for {}", job.kind.as_str(), job.id, job.song_path);
+    let result = run(db, &job);
+
+    db.update_jobs(|jobs| {
+        let Some(j) = jobs.iter_mut().find(|j| j.id == job.id) else {
+            return;
+        };
+
+        match &result {
+            Ok(result) => {
+                j.status = JobStatus::Done;
+                j.error.clear();
+                j.result = result.clone();
+            }
+            Err(e) => {
+                log::warn!("{} job {} failed: {e:?}", j.kind.as_str(), j.id);
+                j.error = e.to_string();
+                if j.attempts >= MAX_ATTEMPTS {
+                    j.status = JobStatus::Failed;
+                } else {
+                    j.status = JobStatus::Pending;
+                    j.next_attempt = now + BASE_BACKOFF_SECS * 2u64.pow(j.attempts - 1);
+                }
+            }
+        }
+    });
+
+    true
+}
+
+/// Runs a job, returning what it found if it reports something.
+fn run(db: &Database, job: &JobEntry) -> anyhow::Result<Option<serde_json::Value>> {
+    let mode = match job.kind {
+        JobKind::Scan => Some(ImportMode::Copy),
+        JobKind::ScanInPlace => Some(ImportMode::InPlace),
+        _ => None,
+    };
+    if let Some(mode) = mode {
+        let report = crate::scanner::scan(db, Path::new(&job.song_path), mode);
+        log::info!(
+            "Scan of {} added {} songs, skipped {} and failed on {}",
+            job.song_path,
+            report.added.len(),
+            report.skipped.len(),
+            report.failed.len()
+        );
+        return Ok(Some(serde_json::to_value(&report)?));
+    }
+    if job.kind == JobKind::Repair {
+        let report = crate::integrity::verify(db, true);
+        return Ok(Some(serde_json::to_value(&report)?));
+    }
+
+    let gone = || anyhow::anyhow!("Song {} no longer exists", job.song_path);
+    let mut song = db.get_song_by_path(&job.song_path).ok_or_else(gone)?;
+
+    // Jobs take a while, so only the fields they computed are written back
+    // to the song as it is stored by then
+    match job.kind {
+        JobKind::Enrich => {
+            if enrich(&mut song)? {
+                let song = db
+                    .update_song(&job.song_path, |s| {
+                        s.recording_mbid = song.recording_mbid;
+                        s.release_group_mbid = song.release_group_mbid;
+                        s.artist_mbids = song.artist_mbids;
+                        if s.genres.iter().all(|g| g.is_empty()) {
+                            s.genres = song.genres;
+                        }
+                    })
+                    .ok_or_else(gone)?;
+                crate::tags::try_write_tags(&song);
+            }
+        }
+        JobKind::Loudness => {
+            crate::loudness::scan_song(&mut song)?;
+            db.update_song(&job.song_path, |s| {
+                s.track_gain = song.track_gain;
+                s.track_peak = song.track_peak;
+            })
+            .ok_or_else(gone)?;
+        }
+        JobKind::Waveform => {
+            crate::waveform::generate(&song.song_path)?;
+        }
+        JobKind::Transcode => {
+            transcode::get_transcoded(
+                &song.song_path,
+                TranscodeFormat::Opus,
+                transcode::DEFAULT_BITRATE_KBPS,
+            )?;
+        }
+        JobKind::Cover => {
+            fetch_cover(&song.song_path, song.release_group_mbid.as_deref())?;
+            let song = db.get_song_by_path(&job.song_path).ok_or_else(gone)?;
+            crate::tags::write_tags(&song)?;
+        }
+        JobKind::Hash => {
+            let hash = crate::library::hash_audio(&song.song_path)?;
+            db.update_song(&job.song_path, |s| s.content_hash = Some(Cow::Owned(hash)))
+                .ok_or_else(gone)?;
+        }
+        JobKind::Listen => crate::history::submit_listen(&song, job.created_at)?,
+        JobKind::Duration => {
+            let duration_secs = crate::song::read_duration_secs(&song.song_path)
+                .ok_or_else(|| anyhow::anyhow!("Can't read the duration of {}", song.song_path))?;
+            db.update_song(&job.song_path, |s| s.duration_secs = Some(duration_secs))
+                .ok_or_else(gone)?;
+        }
+        JobKind::Tags => crate::tags::write_tags(&song)?,
+        JobKind::Lyrics => crate::lyrics::store_embedded(&song.song_path),
+        JobKind::Scan | JobKind::ScanInPlace | JobKind::Repair => unreachable!(),
+    }
+
+    Ok(None)
+}
+
+/// Fills in genres and MusicBrainz IDs the uploader didn't pick. Songs
+/// without an ID are only matched when the best candidate is a close match.
+fn enrich(song: &mut crate::data::SongEntry<'static>) -> anyhow::Result<bool> {
+    let provider = metadata::provider();
+
+    if let Some(mbid) = song.recording_mbid.clone() {
+        if !song.genres.iter().all(|g| g.is_empty()) && !song.artist_mbids.is_empty() {
+            return Ok(false);
+        }
+
+        let Some(rec) = provider.lookup_recording(&mbid)? else {
+            return Ok(false);
+        };
+        if song.genres.iter().all(|g| g.is_empty()) {
+            song.genres = rec.genres.into_iter().map(Cow::Owned).collect();
+        }
+        song.artist_mbids = metadata::merge_artist_ids(&rec.artists)
+            .into_iter()
+            .map(Cow::Owned)
+            .collect();
+
+        return Ok(true);
+    }
+
+    if song.title.is_empty() {
+        return Ok(false);
+    }
+
+    let artist = song.artists.first().map(|a| a.to_string()).filter(|a| !a.is_empty());
+    let album = Some(song.album.to_string()).filter(|a| !a.is_empty());
+    let candidates =
+        metadata::find_candidates(provider, &song.title, artist.as_deref(), album.as_deref())?;
+
+    let Some(best) = candidates.into_iter().next().filter(|c| c.score >= ENRICH_MIN_SCORE) else {
+        return Ok(false);
+    };
+
+    song.recording_mbid = Some(Cow::Owned(best.recording_id));
+    song.release_group_mbid = best.release_group_id.map(Cow::Owned);
+    song.artist_mbids = best.artist_ids.into_iter().map(Cow::Owned).collect();
+    if song.genres.iter().all(|g| g.is_empty()) {
+        song.genres = best.genres.into_iter().map(Cow::Owned).collect();
+    }
+
+    Ok(true)
+}
+
+/// Downloads the front cover of the release group from the Cover Art
+/// Archive, for songs without embedded art.
+fn fetch_cover(song_path: &str, release_group_mbid: Option<&str>) -> anyhow::Result<()> {
+    let cover_path = get_sidecar_path(song_path, "cover");
+    if Path::new(&cover_path).exists() || crate::subsonic::has_embedded_cover(song_path) {
+        return Ok(());
+    }
+    let Some(mbid) = release_group_mbid else {
+        return Ok(());
+    };
+
+    let response =
+        match ureq::get(&format!("https://coverartarchive.org/release-group/{mbid}/front-500"))
+            .call()
+        {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+    let mut data = Vec::new();
+    response.into_reader().read_to_end(&mut data)?;
+    fs::write(cover_path, data)?;
+
+    Ok(())
+}
+
+/// Lists queued and finished jobs, optionally only those of `?song=`.
+pub(crate) fn list(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_auth!(db, req);
+
+    let song = crate::macros::get_query_param(req.url(), "song");
+    let jobs: Vec<JobEntry> = db
+        .get_jobs()
+        .into_iter()
+        .filter(|j| song.as_ref().map_or(true, |s| *s == j.song_path))
+        .collect();
+
+    crate::to_json!(&jobs)
+}
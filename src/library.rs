@@ -0,0 +1,359 @@
+//! The library's metadata as JSON Lines or as a plain CSV with headers, for
+//! editing outside jukbx. An import merges rows back into existing songs,
+//! matched by `song_path` or, if that changed, by the SHA-256 of the decoded
+//! audio, which stays the same when tags are written back to the file. It
+//! never adds or removes songs.
+
+use std::{borrow::Cow, collections::HashMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tiny_http::{Header, Request, Response, ResponseBox};
+
+use crate::data::{Database, SongEntry};
+
+/// `;` or `\` inside a value is escaped with a `\`.
+/// `;` or `\\` inside a value is escaped with a `\\`.
+const CSV_LIST_SEPARATOR: &str = "; ";
+
+#[derive(Clone, Copy)]
+pub(crate) enum LibraryFormat {
+    Jsonl,
+    Csv,
+}
+
+impl LibraryFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "jsonl" => Some(LibraryFormat::Jsonl),
+            "csv" => Some(LibraryFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedSong {
+    song_path: String,
+    #[serde(default)]
+    content_hash: String,
+    title: String,
+    artists: Vec<String>,
+    album: String,
+    #[serde(default)]
+    track: Option<u32>,
+    genres: Vec<String>,
+    track_gain: Option<f64>,
+    track_peak: Option<f64>,
+    album_gain: Option<f64>,
+    album_peak: Option<f64>,
+    recording_mbid: Option<String>,
+    release_group_mbid: Option<String>,
+    artist_mbids: Vec<String>,
+}
+
+/// The same fields as `ExportedSong`, with lists flattened into one column.
+#[derive(Serialize, Deserialize)]
+struct CsvSong {
+    song_path: String,
+    #[serde(default)]
+    content_hash: String,
+    title: String,
+    artists: String,
+    album: String,
+    #[serde(default)]
+    track: Option<u32>,
+    genres: String,
+    track_gain: Option<f64>,
+    track_peak: Option<f64>,
+    album_gain: Option<f64>,
+    album_peak: Option<f64>,
+    recording_mbid: Option<String>,
+    release_group_mbid: Option<String>,
+    artist_mbids: String,
+}
+
+fn join_list(list: &[String]) -> String {
+    list.iter()
+        .map(|v| v.replace('\\', "\\\\").replace(';', "\\;"))
+        .collect::<Vec<_>>()
+        .join(CSV_LIST_SEPARATOR)
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    let mut list = Vec::new();
+    let mut current = String::new();
+
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => current.extend(chars.next()),
+            ';' => list.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    list.push(current);
+
+    list.into_iter()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+impl From<ExportedSong> for CsvSong {
+    fn from(song: ExportedSong) -> Self {
+        CsvSong {
+            artists: join_list(&song.artists),
+            genres: join_list(&song.genres),
+            artist_mbids: join_list(&song.artist_mbids),
+            song_path: song.song_path,
+            content_hash: song.content_hash,
+            title: song.title,
+            album: song.album,
+            track: song.track,
+            track_gain: song.track_gain,
+            track_peak: song.track_peak,
+            album_gain: song.album_gain,
+            album_peak: song.album_peak,
+            recording_mbid: song.recording_mbid,
+            release_group_mbid: song.release_group_mbid,
+        }
+    }
+}
+
+impl From<CsvSong> for ExportedSong {
+    fn from(song: CsvSong) -> Self {
+        ExportedSong {
+            artists: split_list(&song.artists),
+            genres: split_list(&song.genres),
+            artist_mbids: split_list(&song.artist_mbids),
+            song_path: song.song_path,
+            content_hash: song.content_hash,
+            title: song.title,
+            album: song.album,
+            track: song.track,
+            track_gain: song.track_gain,
+            track_peak: song.track_peak,
+            album_gain: song.album_gain,
+            album_peak: song.album_peak,
+            recording_mbid: song.recording_mbid.filter(|m| !m.is_empty()),
+            release_group_mbid: song.release_group_mbid.filter(|m| !m.is_empty()),
+        }
+    }
+}
+
+/// Hashes the samples rather than the file, so the hash doesn't change with
+/// the tags. Done by the `hash` job, it decodes the whole song.
+pub(crate) fn hash_audio(song_path: &str) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    crate::audio::decode_file(Path::new(&format!("./songs/{song_path}")), |samples, _, _| {
+        for sample in samples {
+            hasher.update(sample.to_le_bytes());
+        }
+    })?;
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+fn to_exported(song: &SongEntry) -> ExportedSong {
+    ExportedSong {
+        song_path: song.song_path.to_string(),
+        content_hash: song.content_hash.as_deref().unwrap_or_default().to_string(),
+        title: song.title.to_string(),
+        artists: song.artists.iter().map(|a| a.to_string()).collect(),
+        album: song.album.to_string(),
+        track: song.track,
+        genres: song.genres.iter().map(|g| g.to_string()).collect(),
+        track_gain: song.track_gain,
+        track_peak: song.track_peak,
+        album_gain: song.album_gain,
+        album_peak: song.album_peak,
+        recording_mbid: song.recording_mbid.as_ref().map(|m| m.to_string()),
+        release_group_mbid: song.release_group_mbid.as_ref().map(|m| m.to_string()),
+        artist_mbids: song.artist_mbids.iter().map(|m| m.to_string()).collect(),
+    }
+}
+
+pub(crate) fn export(db: &Database, format: LibraryFormat) -> anyhow::Result<String> {
+    let songs = db.get_all_songs().iter().map(to_exported).collect::<Vec<_>>();
+
+    match format {
+        LibraryFormat::Jsonl => {
+            let mut out = String::new();
+            for song in songs {
+                out.push_str(&serde_json::to_string(&song)?);
+                out.push('\n');
+            }
+            Ok(out)
+        }
+        LibraryFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for song in songs {
+                writer.serialize(CsvSong::from(song))?;
+            }
+            Ok(String::from_utf8(writer.into_inner()?)?)
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+pub(crate) struct ImportReport {
+    pub updated: Vec<String>,
+    pub unchanged: Vec<String>,
+    /// Rows that match no song, by their `song_path`
+    pub unmatched: Vec<String>,
+}
+
+fn parse(content: &str, format: LibraryFormat) -> anyhow::Result<Vec<ExportedSong>> {
+    match format {
+        LibraryFormat::Jsonl => content
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| Ok(serde_json::from_str(l)?))
+            .collect(),
+        LibraryFormat::Csv => csv::Reader::from_reader(content.as_bytes())
+            .deserialize::<CsvSong>()
+            .map(|r| Ok(r?.into()))
+            .collect(),
+    }
+}
+
+/// Sets the fields of an export row on a song, except the ones that identify
+/// it.
+fn merge_row(song: &mut SongEntry<'static>, row: &ExportedSong) {
+    song.title = Cow::Owned(row.title.clone());
+    song.artists = row.artists.iter().cloned().map(Cow::Owned).collect();
+    song.album = Cow::Owned(row.album.clone());
+    song.track = row.track;
+    song.genres = row.genres.iter().cloned().map(Cow::Owned).collect();
+    song.track_gain = row.track_gain;
+    song.track_peak = row.track_peak;
+    song.album_gain = row.album_gain;
+    song.album_peak = row.album_peak;
+    song.recording_mbid = row.recording_mbid.clone().map(Cow::Owned);
+    song.release_group_mbid = row.release_group_mbid.clone().map(Cow::Owned);
+    song.artist_mbids = row.artist_mbids.iter().cloned().map(Cow::Owned).collect();
+}
+
+/// Merges exported rows into the library. Nothing is written if any row
+/// fails to parse.
+pub(crate) fn import(
+    db: &Database,
+    content: &str,
+    format: LibraryFormat,
+) -> anyhow::Result<ImportReport> {
+    let rows = parse(content, format)?;
+    let songs = db.get_all_songs();
+    let mut report = ImportReport::default();
+
+    let by_hash: HashMap<&str, usize> = songs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, s)| Some((s.content_hash.as_deref()?, i)))
+        .collect();
+
+    for row in rows {
+        let mut index = songs.iter().position(|s| s.song_path == row.song_path);
+
+        if index.is_none() && !row.content_hash.is_empty() {
+            index = by_hash.get(row.content_hash.as_str()).copied();
+        }
+
+        let Some(index) = index else {
+            report.unmatched.push(row.song_path);
+            continue;
+        };
+
+        let song = &songs[index];
+        let mut merged = song.clone();
+        merge_row(&mut merged, &row);
+
+        if serde_json::to_value(&merged)? == serde_json::to_value(song)? {
+            report.unchanged.push(song.song_path.to_string());
+            continue;
+        }
+
+        // Only the exported fields are written, to the song as it is stored
+        // by now
+        match db.update_song(&song.song_path, |s| merge_row(s, &row)) {
+            Some(updated) => {
+                report.updated.push(song.song_path.to_string());
+                crate::tags::try_write_tags(&updated);
+            }
+            None => report.unmatched.push(row.song_path),
+        }
+    }
+
+    Ok(report)
+}
+
+pub(crate) fn import_file(db: &Database, path: &Path) -> anyhow::Result<ImportReport> {
+    let format = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .and_then(LibraryFormat::parse)
+        .ok_or(anyhow::anyhow!("Expected a .jsonl or .csv file"))?;
+
+    import(db, &std::fs::read_to_string(path)?, format)
+}
+
+/// Serves `api/export.jsonl` and `api/export.csv`.
+pub(crate) fn export_library(db: &Database, req: &mut Request, format: LibraryFormat) -> ResponseBox {
+    let _ = crate::try_auth!(db, req);
+
+    let body = crate::try_unwrap!(export(db, format));
+    let (content_type, extension) = match format {
+        LibraryFormat::Jsonl => ("application/jsonl", "jsonl"),
+        LibraryFormat::Csv => ("text/csv", "csv"),
+    };
+    let disposition = format!("attachment; filename=\"jukbx-library.{extension}\"");
+
+    Response::from_string(body)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap())
+        .with_header(Header::from_bytes(&b"Content-Disposition"[..], disposition.as_bytes()).unwrap())
+        .with_status_code(200)
+        .boxed()
+}
+
+/// Takes an export as the request body, `?format=jsonl` (the default) or
+/// `?format=csv`. Only admins may import.
+pub(crate) fn import_library(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_admin!(db, req);
+
+    let format = crate::macros::get_query_param(req.url(), "format");
+    let Some(format) = LibraryFormat::parse(format.as_deref().unwrap_or("jsonl")) else {
+        return Response::from_string("Unknown format").with_status_code(400).boxed();
+    };
+
+    let mut content = String::new();
+    crate::try_unwrap!(req.as_reader().read_to_string(&mut content));
+
+    let report = crate::try_unwrap!(import(db, &content, format));
+
+    crate::to_json!(&report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_values_with_separators_survive_the_csv() {
+        let list = vec!["Earth; Wind & Fire".to_string(), "AC\\DC".to_string(), "Queen".to_string()];
+
+        let joined = join_list(&list);
+
+        assert_eq!(joined, "Earth\\; Wind & Fire; AC\\\\DC; Queen");
+        assert_eq!(split_list(&joined), list);
+    }
+
+    #[test]
+    fn hand_written_lists_split_on_semicolons() {
+        assert_eq!(split_list("A;B ; C;"), vec!["A", "B", "C"]);
+        assert!(split_list("").is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use ebur128::{EbuR128, Mode};
+use lofty::{
+    config::WriteOptions,
+    file::{AudioFile, TaggedFileExt},
+    tag::{ItemKey, Tag},
+};
+
+use crate::{
+    audio,
+    data::{Database, SongEntry},
+    tags,
+};
+
+/// ReplayGain 2.0 reference level
+const REFERENCE_LUFS: f64 = -18.0;
+
+pub(crate) struct Scan {
+    state: EbuR128,
+    peak: f64,
+}
+
+impl Scan {
+    pub fn gain(&self) -> anyhow::Result<f64> {
+        Ok(REFERENCE_LUFS - self.state.loudness_global()?)
+    }
+
+    pub fn peak(&self) -> f64 {
+        self.peak
+    }
+}
+
+/// Measures the integrated loudness and true peak of an audio file.
+pub(crate) fn scan(path: &Path) -> anyhow::Result<Scan> {
+    let mut state: Option<EbuR128> = None;
+    let mut channel_count = 0;
+    let mut error = None;
+
+    audio::decode_file(path, |samples, channels, rate| {
+        if error.is_some() {
+            return;
+        }
+
+        if state.is_none() {
+            match EbuR128::new(channels as u32, rate, Mode::I | Mode::TRUE_PEAK) {
+                Ok(s) => {
+                    state = Some(s);
+                    channel_count = channels as u32;
+                }
+                Err(e) => {
+                    error = Some(e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(state) = &mut state {
+            if let Err(e) = state.add_frames_f32(samples) {
+                error = Some(e);
+            }
+        }
+    })?;
+
+    if let Some(e) = error {
+        return Err(e.into());
+    }
+
+    let Some(state) = state else {
+        return Err(anyhow::anyhow!("No audio decoded"));
+    };
+
+    let mut peak = 0.0f64;
+    for channel in 0..channel_count {
+        peak = peak.max(state.true_peak(channel)?);
+    }
+
+    Ok(Scan { state, peak })
+}
+
+/// Fills in the track gain and peak of a song that was just added. Album
+/// values need the rest of the album, so those only come from the
+/// `loudness-scan` command.
+pub(crate) fn scan_song(song: &mut SongEntry) -> anyhow::Result<()> {
+    let scan = scan(Path::new(&format!("./songs/{}", song.song_path)))?;
+
+    song.track_gain = Some(scan.gain()?);
+    song.track_peak = Some(scan.peak());
+
+    Ok(())
+}
+
+/// Scans every song in the library, computing track and album gain.
+pub(crate) fn backfill(db: &Database, write_tags: bool) {
+    let songs = db.get_all_songs();
+
+    let mut albums: BTreeMap<(String, String), Vec<(SongEntry<'static>, Scan)>> = BTreeMap::new();
+    let mut singles = Vec::new();
+
+    for song in songs {
+        let path = format!("./songs/{}", song.song_path);
+        let scan = match scan(Path::new(&path)) {
+            Ok(scan) => scan,
+            Err(e) => {
+                log::warn!("Failed to scan loudness of {path}: {e:?}");
+                continue;
+            }
+        };
+
+        log::info!("Scanned {path}");
+
+        if song.album.is_empty() {
+            singles.push((song, scan));
+        } else {
+            let artist = song.artists.first().map(|a| a.to_string()).unwrap_or_default();
+            albums
+                .entry((song.album.to_string(), artist))
+                .or_default()
+                .push((song, scan));
+        }
+    }
+
+    // (song path, track gain, track peak, album gain, album peak)
+    let mut gains = Vec::new();
+
+    for (song, scan) in singles {
+        let Ok(gain) = scan.gain() else {
+            continue;
+        };
+
+        gains.push((song.song_path, gain, scan.peak(), None, None));
+    }
+
+    for (_, tracks) in albums {
+        let album_gain = EbuR128::loudness_global_multiple(tracks.iter().map(|(_, s)| &s.state))
+            .ok()
+            .map(|loudness| REFERENCE_LUFS - loudness);
+        let album_peak = tracks.iter().map(|(_, s)| s.peak()).fold(0.0, f64::max);
+
+        for (song, scan) in tracks {
+            let Ok(gain) = scan.gain() else {
+                continue;
+            };
+
+            gains.push((song.song_path, gain, scan.peak(), album_gain, album_gain.map(|_| album_peak)));
+        }
+    }
+
+    let mut count = 0;
+
+    for (song_path, track_gain, track_peak, album_gain, album_peak) in gains {
+        let updated = db.update_song(&song_path, |s| {
+            s.track_gain = Some(track_gain);
+            s.track_peak = Some(track_peak);
+            s.album_gain = album_gain;
+            s.album_peak = album_peak;
+        });
+        let Some(song) = updated else {
+            continue;
+        };
+        count += 1;
+
+        if write_tags {
+            let path = format!("./songs/{song_path}");
+            if let Err(e) = write_replaygain_tags(Path::new(&path), &song) {
+                log::warn!("Failed to write ReplayGain tags to {path}: {e:?}");
+            }
+        }
+    }
+
+    log::info!("Updated loudness of {count} songs");
+}
+
+pub(crate) fn write_replaygain_tags(path: &Path, song: &SongEntry) -> anyhow::Result<()> {
+    if fs::symlink_metadata(path)?.file_type().is_symlink() {
+        log::debug!("Not writing ReplayGain tags to {}, it was scanned in place", path.display());
+        return Ok(());
+    }
+
+    let _guard = tags::TAG_MUTEX.lock().unwrap();
+
+    let mut file = lofty::read_from_path(path)?;
+
+    if file.primary_tag().is_none() {
+        let tag_type = file.primary_tag_type();
+        file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = file.primary_tag_mut().unwrap();
+
+    let values = [
+        (ItemKey::ReplayGainTrackGain, song.track_gain.map(|g| format!("{g:.2} dB"))),
+        (ItemKey::ReplayGainTrackPeak, song.track_peak.map(|p| format!("{p:.6}"))),
+        (ItemKey::ReplayGainAlbumGain, song.album_gain.map(|g| format!("{g:.2} dB"))),
+        (ItemKey::ReplayGainAlbumPeak, song.album_peak.map(|p| format!("{p:.6}"))),
+    ];
+    for (key, value) in values {
+        if let Some(value) = value {
+            tag.insert_text(key, value);
+        }
+    }
+
+    file.save_to_path(path, WriteOptions::default())?;
+
+    Ok(())
+}
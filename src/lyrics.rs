@@ -0,0 +1,284 @@
+//! Lyrics, kept as a `.lrc` sidecar next to the song. They are either plain
+//! text or time-synced LRC, which is what any line starting with a
+//! `[mm:ss.xx]` timestamp makes them.
+//!
+//! On upload they are taken from the file's tags: a `SYLT` frame, turned
+//! into LRC, or else the `USLT` lyrics. `api/setLyrics` replaces them.
+
+use std::{fs, io, path::Path};
+
+use lofty::{file::TaggedFileExt, probe::Probe, tag::ItemKey};
+use serde::{Deserialize, Serialize};
+use tiny_http::{Request, Response, ResponseBox};
+
+use crate::{data::Database, song::get_sidecar_path};
+
+const MAX_LYRICS_LEN: usize = 256 * 1024;
+/// `SYLT` timestamps in milliseconds, rather than MPEG frames
+const SYLT_MILLISECONDS: u8 = 2;
+
+#[derive(Serialize)]
+struct LyricLine {
+    time_ms: u64,
+    text: String,
+}
+
+pub(crate) fn get_lyrics(song_path: &str) -> Option<String> {
+    fs::read_to_string(get_sidecar_path(song_path, "lrc")).ok()
+}
+
+/// Stores the lyrics of a song, empty lyrics remove them.
+pub(crate) fn set_lyrics(song_path: &str, lyrics: &str) -> io::Result<()> {
+    let path = get_sidecar_path(song_path, "lrc");
+
+    if lyrics.trim().is_empty() {
+        return match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+
+    fs::write(path, lyrics)
+}
+
+/// Keeps the lyrics embedded in a newly added song, if it has any.
+pub(crate) fn store_embedded(song_path: &str) {
+    let Some(lyrics) = extract(song_path) else {
+        return;
+    };
+
+    if let Err(e) = set_lyrics(song_path, &lyrics) {
+        log::warn!("Failed to store lyrics of {song_path}: {e:?}");
+    }
+}
+
+/// Synced lyrics if the file has them, plain ones otherwise.
+fn extract(song_path: &str) -> Option<String> {
+    let path = format!("./songs/{song_path}");
+
+    if let Some(lyrics) = fs::read(&path).ok().and_then(|data| read_sylt(&data)) {
+        return Some(lyrics);
+    }
+
+    let file = Probe::open(&path).and_then(|p| p.read()).ok()?;
+    let tag = file.primary_tag().or(file.first_tag())?;
+
+    tag.get_string(&ItemKey::Lyrics)
+        .map(|l| l.to_string())
+        .filter(|l| !l.trim().is_empty())
+}
+
+fn read_u32(bytes: &[u8], syncsafe: bool) -> usize {
+    bytes.iter().fold(0usize, |n, b| {
+        if syncsafe {
+            (n << 7) | (*b & 0x7F) as usize
+        } else {
+            (n << 8) | *b as usize
+        }
+    })
+}
+
+/// Finds the first `SYLT` frame in an ID3v2.3 or 2.4 tag. lofty only keeps
+/// these as raw frames, so the tag is walked here.
+fn read_sylt(data: &[u8]) -> Option<String> {
+    if data.len() < 10 || !data.starts_with(b"ID3") {
+        return None;
+    }
+    let version = data[3];
+    let flags = data[5];
+    // Unsynchronised tags would need undoing first, they are rare enough
+    if !(version == 3 || version == 4) || flags & 0x80 != 0 {
+        return None;
+    }
+
+    let end = (10 + read_u32(&data[6..10], true)).min(data.len());
+    let mut pos = 10;
+    if flags & 0x40 != 0 {
+        let size = read_u32(data.get(10..14)?, version == 4);
+        // The size of a 2.3 extended header leaves out its own size field
+        pos += if version == 4 { size } else { size + 4 };
+    }
+
+    while pos + 10 <= end {
+        let id = &data[pos..pos + 4];
+        if id[0] == 0 {
+            break; // Padding
+        }
+        let size = read_u32(&data[pos + 4..pos + 8], version == 4);
+        let body = data.get(pos + 10..pos + 10 + size)?;
+
+        if id == b"SYLT" {
+            return parse_sylt(body);
+        }
+        pos += 10 + size;
+    }
+
+    None
+}
+
+/// Splits a string terminated by the encoding's null off the front.
+fn take_text(data: &[u8], encoding: u8) -> Option<(String, &[u8])> {
+    let is_wide = encoding == 1 || encoding == 2;
+    let step = if is_wide { 2 } else { 1 };
+
+    let len = (0..data.len().saturating_sub(step - 1))
+        .step_by(step)
+        .find(|&i| data[i..i + step].iter().all(|b| *b == 0))?;
+    let bytes = &data[..len];
+    let rest = &data[len + step..];
+
+    let text = match encoding {
+        0 => bytes.iter().map(|&b| b as char).collect(),
+        1 | 2 => {
+            let mut units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            // Encoding 1 starts with a byte order mark, which may say LE
+            if encoding == 1 {
+                match units.first() {
+                    Some(0xFFFE) => {
+                        units = units.iter().skip(1).map(|u| u.swap_bytes()).collect();
+                    }
+                    Some(0xFEFF) => {
+                        units.remove(0);
+                    }
+                    _ => {}
+                }
+            }
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    };
+
+    Some((text, rest))
+}
+
+/// Turns a `SYLT` frame into LRC.
+fn parse_sylt(body: &[u8]) -> Option<String> {
+    let encoding = *body.first()?;
+    let timestamp_format = *body.get(4)?;
+    if timestamp_format != SYLT_MILLISECONDS {
+        return None;
+    }
+
+    // Skip the language and content type, then the content descriptor
+    let (_, mut rest) = take_text(body.get(6..)?, encoding)?;
+
+    let mut lrc = String::new();
+    while let Some((text, after)) = take_text(rest, encoding) {
+        let Some(time) = after.get(..4) else {
+            break;
+        };
+        let time_ms = u32::from_be_bytes([time[0], time[1], time[2], time[3]]);
+        rest = &after[4..];
+
+        // Lines usually carry their line break at the start
+        let text = text.trim_matches(|c| c == '\n' || c == '\r');
+        lrc.push_str(&format!(
+            "[{:02}:{:02}.{:02}]{text}\n",
+            time_ms / 60000,
+            time_ms / 1000 % 60,
+            time_ms % 1000 / 10
+        ));
+    }
+
+    (!lrc.is_empty()).then_some(lrc)
+}
+
+/// Milliseconds of a `mm:ss.xx` LRC timestamp.
+fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let (minutes, seconds) = timestamp.split_once(':')?;
+    let minutes: u64 = minutes.trim().parse().ok()?;
+    let seconds: f64 = seconds.trim().parse().ok()?;
+
+    Some(minutes * 60000 + (seconds * 1000.0).round() as u64)
+}
+
+/// The timed lines of LRC lyrics, in order. Empty for plain lyrics.
+fn parse_lrc(lyrics: &str) -> Vec<LyricLine> {
+    let mut offset_ms = 0i64;
+    let mut lines = Vec::new();
+
+    for line in lyrics.lines() {
+        let mut rest = line.trim();
+        let mut times = Vec::new();
+
+        // A line can have several timestamps, for repeated lines
+        while let Some((tag, after)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+            if let Some(offset) = tag.strip_prefix("offset:") {
+                offset_ms = offset.trim().parse().unwrap_or(0);
+            } else if let Some(time) = parse_timestamp(tag) {
+                times.push(time);
+            }
+            rest = after;
+        }
+
+        for time in times {
+            lines.push(LyricLine {
+                // A positive offset shows lines earlier
+                time_ms: (time as i64 - offset_ms).max(0) as u64,
+                text: rest.trim().to_string(),
+            });
+        }
+    }
+
+    lines.sort_by_key(|l| l.time_ms);
+    lines
+}
+
+/// Serves `/api/lyrics/{song}`. `lines` is only filled for synced lyrics.
+pub(crate) fn get_lyrics_response(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_data_access!(db, req);
+
+    let url = req.url();
+    let Some(song) = url.split('?').next().and_then(|p| p.rsplit('/').next()) else {
+        return Response::from_string("").with_status_code(404).boxed();
+    };
+    let song = url_escape::decode(song).into_owned();
+
+    if db.get_song_by_path(&song).is_none() || !Path::new(&format!("./songs/{song}")).is_file() {
+        return Response::from_string("").with_status_code(404).boxed();
+    }
+
+    let Some(text) = get_lyrics(&song) else {
+        return Response::from_string("").with_status_code(404).boxed();
+    };
+    let lines = parse_lrc(&text);
+
+    #[derive(Serialize)]
+    struct LyricsResponse {
+        synced: bool,
+        text: String,
+        lines: Vec<LyricLine>,
+    }
+
+    crate::to_json!(&LyricsResponse {
+        synced: !lines.is_empty(),
+        text,
+        lines,
+    })
+}
+
+#[derive(Deserialize)]
+struct SetLyricsRequest {
+    song_path: String,
+    lyrics: String,
+}
+
+/// Replaces the lyrics of a song with plain text or LRC, or removes them if
+/// `lyrics` is empty.
+pub(crate) fn set_lyrics_request(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_auth!(db, req);
+    let r: SetLyricsRequest = crate::try_json!(req);
+
+    crate::require!(r.lyrics.len() < MAX_LYRICS_LEN);
+
+    if db.get_song_by_path(&r.song_path).is_none() {
+        return Response::from_string("").with_status_code(404).boxed();
+    }
+
+    crate::try_unwrap!(set_lyrics(&r.song_path, &r.lyrics));
+
+    Response::from_string("{}").with_status_code(200).boxed()
+}
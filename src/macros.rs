@@ -87,6 +87,50 @@ pub fn get_auth(req: &Request) -> Option<(String, String)> {
     Some((username.to_string(), password.to_string()))
 }
 
+pub fn get_query_param(url: &str, name: &str) -> Option<String> {
+    let (_, query) = url.split_once('?')?;
+
+    query
+        .split('&')
+        .map(|kv| kv.split_once('=').unwrap_or((kv, "")))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| url_escape::decode_component(&v.replace('+', " ")).into_owned())
+}
+
+pub fn get_header<'a>(req: &'a Request, name: &'static str) -> Option<&'a str> {
+    req.headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[macro_export]
+macro_rules! try_data_access {
+    ($db:expr, $req:expr) => {{
+        match crate::song::get_data_access($db, $req) {
+            Ok(who) => who,
+            Err(status) => {
+                return Response::from_string("").with_status_code(status).boxed();
+            }
+        }
+    }};
+}
+
 #[macro_export]
 macro_rules! try_auth {
     ($db:expr, $req:expr) => {{
@@ -129,3 +173,52 @@ macro_rules! try_auth {
         user
     }};
 }
+
+/// Admins are listed by username in `JUKBX_ADMINS`, separated by commas.
+pub fn is_admin(user: &str) -> bool {
+    std::env::var("JUKBX_ADMINS")
+        .map(|admins| admins.split(',').any(|a| a.trim() == user))
+        .unwrap_or(false)
+}
+
+#[macro_export]
+macro_rules! try_admin {
+    ($db:expr, $req:expr) => {{
+        let user = crate::try_auth!($db, $req);
+
+        if !crate::macros::is_admin(&user) {
+            return Response::from_string("Not an admin")
+                .with_status_code(403)
+                .boxed();
+        }
+
+        user
+    }};
+}
+
+/// The logged in user, for endpoints that also work without a login.
+pub fn get_optional_user(db: &crate::data::Database, req: &Request) -> Option<String> {
+    use sha2::{Digest, Sha256};
+
+    let (user, pass) = get_auth(req)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(pass.as_bytes());
+    let base64_pass = base64::encode(hasher.finalize());
+
+    db.get_user(&user, &base64_pass)
+}
+
+/// A quoted strong `ETag` for a response body.
+pub fn get_etag(body: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    hasher.update(body.as_bytes());
+    let hash = hasher.finalize()[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+
+    format!("\"{hash}\"")
+}
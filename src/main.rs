@@ -1,21 +1,51 @@
-use std::{env, fs, thread};
+use std::{
+    env, fs,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use data::Database;
 use log::{debug, info};
 use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
 use rusqlite_migration::{Migrations, M};
 use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGINT, SIGTERM};
 use sha2::{Digest, Sha256};
 use tiny_http::{Request, Response, ResponseBox};
 
 mod macros;
+mod audio;
+mod autodj;
+mod backup;
+mod brainz_cache;
 mod data;
+mod fingerprint;
+mod history;
+mod integrity;
+mod jobs;
+mod library;
+mod loudness;
+mod lyrics;
+mod metadata;
 use macros::*;
 
 // mod category;
 // mod entries;
 
+mod playlist;
+mod pool;
+mod queue;
+mod radio;
+mod ratings;
+mod scanner;
 mod song;
+mod subsonic;
+mod tags;
+mod transcode;
+mod waveform;
 
 fn main() {
     env_logger::init();
@@ -23,7 +53,15 @@ fn main() {
 
     info!("Starting jukbx");
 
-    let mut db = Database::open("./songs.csv".into(), "./users.csv".into(), "./whitelist.csv".into());
+    let mut db = Database::open(
+        "./songs.csv".into(),
+        "./users.csv".into(),
+        "./whitelist.csv".into(),
+        "./tokens.csv".into(),
+        "./jobs.csv".into(),
+        "./plays.csv".into(),
+        "./ratings.csv".into(),
+    );
 
     let mut args = env::args();
     let _ = args.next();
@@ -40,18 +78,152 @@ fn main() {
             db.add_user(&user, &base64_pass);
 
             log::info!("Added new user '{user}'")
+        } else if arg == "apipass" {
+            let user = args.next().expect("Expected username");
+
+            let api_pass = generate_api_password();
+            if db.set_api_password(&user, &api_pass) {
+                println!("{api_pass}");
+            } else {
+                log::error!("No user named '{user}'");
+            }
+        } else if arg == "loudness-scan" {
+            let write_tags = args.any(|a| a == "--write-tags");
+
+            loudness::backfill(&db, write_tags);
+        } else if arg == "purge-cache" {
+            let expired_only = args.any(|a| a == "--expired");
+
+            let removed = brainz_cache::purge(expired_only);
+
+            log::info!("Removed {removed} MusicBrainz cache entries")
+        } else if arg == "scan" {
+            let mut dir = None;
+            let mut mode = scanner::ImportMode::Copy;
+            for arg in args {
+                if arg == "--in-place" {
+                    mode = scanner::ImportMode::InPlace;
+                } else {
+                    dir = Some(arg.into());
+                }
+            }
+            let dir = dir
+                .or(scanner::get_music_dir())
+                .expect("Expected a directory or JUKBX_MUSIC_DIR");
+
+            let report = scanner::scan(&db, &dir, mode);
+
+            for failure in &report.failed {
+                log::warn!("Failed: {}: {}", failure.path, failure.error);
+            }
+            log::info!(
+                "Added {} songs, skipped {}, {} failed",
+                report.added.len(),
+                report.skipped.len(),
+                report.failed.len()
+            );
+        } else if arg == "verify" {
+            let repair = args.any(|a| a == "--repair");
+
+            let report = integrity::verify(&db, repair);
+
+            for (problem, songs) in [
+                ("Missing", &report.missing),
+                ("Orphan", &report.orphans),
+                ("Unreadable", &report.unreadable),
+                ("Duplicate", &report.duplicates),
+            ] {
+                for song in songs {
+                    log::warn!("{problem}: {song}");
+                }
+            }
+            if report.is_clean() {
+                log::info!("Library is consistent");
+            } else if !repair {
+                log::info!("Dry run, use --repair to fix");
+            }
+        } else if arg == "backup" {
+            let out = args.next().expect("Expected output path");
+            let include_audio = args.any(|a| a == "--with-audio");
+
+            match backup::create(out.as_ref(), include_audio) {
+                Ok(count) => log::info!("Backed up {count} files to {out}"),
+                Err(e) => log::error!("Backup failed: {e:?}"),
+            }
+        } else if arg == "restore" {
+            let archive = args.next().expect("Expected backup path");
+
+            match backup::restore(archive.as_ref()) {
+                Ok(count) => log::info!("Restored {count} files from {archive}"),
+                Err(e) => log::error!("Restore failed: {e:?}"),
+            }
+        } else if arg == "export" {
+            let format = args
+                .next()
+                .as_deref()
+                .and_then(library::LibraryFormat::parse)
+                .expect("Expected jsonl or csv");
+
+            let out = library::export(&db, format).unwrap();
+            match args.next() {
+                Some(path) => fs::write(path, out).unwrap(),
+                None => print!("{out}"),
+            }
+        } else if arg == "import" {
+            let path = args.next().expect("Expected a .jsonl or .csv file");
+
+            match library::import_file(&db, path.as_ref()) {
+                Ok(report) => {
+                    for song in &report.unmatched {
+                        log::warn!("No song matches {song}");
+                    }
+                    log::info!(
+                        "Updated {} songs, {} unchanged, {} unmatched",
+                        report.updated.len(),
+                        report.unchanged.len(),
+                        report.unmatched.len()
+                    );
+                }
+                Err(e) => log::error!("Import failed: {e:?}"),
+            }
+        } else if arg == "song" {
+            match args.next().as_deref() {
+                Some("refresh-metadata") => metadata::refresh(&db, metadata::provider()),
+                _ => log::error!("Usage: song refresh-metadata"),
+            }
         }
 
         return;
     }
 
+    jobs::start(db.clone());
+    queue::start(db.clone());
+    {
+        let db = db.clone();
+        std::thread::spawn(move || song::backfill_durations(&db));
+    }
+
     let server = tiny_http::Server::http("127.0.0.1:8089").unwrap();
 
-    info!("Listening for HTTP requests...");
-    for mut req in server.incoming_requests() {
+    let terminate = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, terminate.clone()).unwrap();
+    }
+
+    let pool = {
         let db = db.clone();
-        thread::spawn(move || {
-            let response = get_response(db, &mut req);
+        pool::WorkerPool::from_env(move |mut req| {
+            // Event and audio streams stay open, so they get a thread of their own
+            if req.url().starts_with("/api/queue/events") {
+                queue::subscribe(&db, req);
+                return;
+            }
+            if req.url().split('?').next() == Some("/stream") {
+                radio::listen(&db, req);
+                return;
+            }
+
+            let response = get_response(db.clone(), &mut req);
 
             debug!(
                 "{} {} => {}",
@@ -59,10 +231,32 @@ fn main() {
                 req.url(),
                 response.status_code().0
             );
-    
+
             let _ = req.respond(response);
-        });
+        })
+    };
+
+    info!("Listening for HTTP requests...");
+    while !terminate.load(Ordering::Relaxed) {
+        let req = match server.recv_timeout(Duration::from_millis(500)) {
+            Ok(Some(req)) => req,
+            Ok(None) => continue,
+            Err(e) => {
+                log::error!("Failed to receive request: {e:?}");
+                continue;
+            }
+        };
+
+        if let Err(req) = pool.execute(req) {
+            log::warn!("Request queue full, rejecting {} {}", req.method(), req.url());
+            let _ = req.respond(Response::from_string("Service Unavailable").with_status_code(503));
+        }
     }
+
+    info!("Shutting down, waiting for in-flight requests...");
+    pool.join();
+    pool::stop_streams();
+    info!("Stopped");
 }
 
 fn get_response(
@@ -80,19 +274,57 @@ fn get_response(
     }
 
     if let Some((_, path)) = url.split_once("/") {
+        let path = path.split('?').next().unwrap_or(path);
         if path.starts_with("songs/") {
             return song::get_audio_page(&db, req);
         }
         if path.starts_with("data/") {
             return song::get_audio_data(&db, req);
         }
+        if path.starts_with("api/waveform/") {
+            return waveform::get_waveform(&db, req);
+        }
+        if path.starts_with("api/lyrics/") {
+            return lyrics::get_lyrics_response(&db, req);
+        }
+        if path.starts_with("rest/") {
+            return subsonic::handle(&db, req);
+        }
 
         match path {
             "api/login" => return login(&db, req),
             "api/updatePassword" => return update_password(&db, req),
             "api/probeSong" => return song::probe(&db, req),
+            "api/applyCandidate" => return song::apply_candidate(&db, req),
             "api/addSong" => return song::add(&db, req),
+            "api/editSong" => return song::edit(&db, req),
+            "api/jobs" => return jobs::list(&db, req),
+            "api/autoDj" => return autodj::auto_dj(&db, req),
+            "api/queue" => return queue::get_queue(&db, req),
+            "api/queue/add" => return queue::add(&db, req),
+            "api/queue/vote" => return queue::vote(&db, req),
+            "api/queue/move" => return queue::move_entry(&db, req),
+            "api/queue/remove" => return queue::remove(&db, req),
+            "api/queue/skip" => return queue::skip(&db, req),
+            "api/queue/playback" => return queue::playback(&db, req),
+            "api/setLyrics" => return lyrics::set_lyrics_request(&db, req),
+            "api/scrobble" => return history::scrobble(&db, req),
+            "api/setRating" => return ratings::set_rating(&db, req),
+            "api/ratings" => return ratings::list_ratings(&db, req),
+            "api/history" => return history::history(&db, req),
+            "api/mostPlayed" => return history::most_played(&db, req),
+            "api/recentlyPlayed" => return history::recently_played(&db, req),
+            "api/scan" => return scanner::scan_library(&db, req),
+            "api/verify" => return integrity::verify_library(&db, req),
             "api/listSongs" => return song::list(&db, req),
+            "api/createShareToken" => return create_share_token(&db, req),
+            "api/createApiPassword" => return create_api_password(&db, req),
+            "api/export.m3u8" => return playlist::export(&db, req, playlist::PlaylistFormat::M3u8),
+            "api/export.xspf" => return playlist::export(&db, req, playlist::PlaylistFormat::Xspf),
+            "api/export.pls" => return playlist::export(&db, req, playlist::PlaylistFormat::Pls),
+            "api/export.jsonl" => return library::export_library(&db, req, library::LibraryFormat::Jsonl),
+            "api/export.csv" => return library::export_library(&db, req, library::LibraryFormat::Csv),
+            "api/import" => return library::import_library(&db, req),
             // "api/listAlbums" => return album::list(db, req),
             // "api/listArtists" => return artist::list(db, req),
             // "api/listGenres" => return genre::list(db, req),
@@ -144,3 +376,52 @@ fn update_password(db: &Database, req: &mut Request) -> ResponseBox {
     
     Response::from_string("{}").with_status_code(200).boxed()
 }
+
+fn create_share_token(db: &Database, req: &mut Request) -> ResponseBox {
+    use rand::{distributions::Alphanumeric, Rng};
+
+    let user = try_auth!(db, req);
+
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+
+    db.add_share_token(&token, &user);
+
+    log::info!("Created share token for '{user}'");
+
+    #[derive(Serialize)]
+    struct ShareTokenResponse {
+        token: String,
+    }
+
+    to_json!(&ShareTokenResponse { token })
+}
+
+fn generate_api_password() -> String {
+    use rand::{distributions::Alphanumeric, Rng};
+
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect()
+}
+
+/// Subsonic clients authenticate with `md5(password + salt)`, which can't be
+/// checked against the hashed login password, so they get a separate one.
+fn create_api_password(db: &Database, req: &mut Request) -> ResponseBox {
+    let user = try_auth!(db, req);
+
+    let api_password = generate_api_password();
+    require!(db.set_api_password(&user, &api_password));
+
+    #[derive(Serialize)]
+    struct ApiPasswordResponse {
+        api_password: String,
+    }
+
+    to_json!(&ApiPasswordResponse { api_password })
+}
@@ -0,0 +1,629 @@
+//! Where song metadata comes from. `get_metadata` in `song` only talks to a
+//! `MetadataProvider`: MusicBrainz normally, or a fixture file when
+//! `JUKBX_METADATA_FIXTURES` is set, so probing works without the network.
+
+use std::{
+    borrow::Cow,
+    env, fs,
+    sync::{LazyLock, Mutex},
+    thread,
+    time::Duration,
+};
+
+use musicbrainz_rs_nova::{
+    entity::{
+        recording::{Recording, RecordingSearchQuery},
+        release_group::{ReleaseGroup, ReleaseGroupSearchQuery},
+    },
+    Fetch, Search,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::data::Database;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ArtistInfo {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ReleaseInfo {
+    pub id: String,
+    pub title: String,
+    pub date: Option<String>,
+    pub release_group_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ReleaseGroupInfo {
+    pub id: String,
+    pub title: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct RecordingInfo {
+    pub id: String,
+    pub title: String,
+    pub artists: Vec<ArtistInfo>,
+    pub releases: Vec<ReleaseInfo>,
+    pub genres: Vec<String>,
+}
+
+/// One possible match for an uploaded file, scored against its tags.
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct Candidate {
+    pub recording_id: String,
+    pub title: String,
+    pub artists: Vec<String>,
+    pub artist_ids: Vec<String>,
+    pub release_id: Option<String>,
+    pub release_group_id: Option<String>,
+    pub release: Option<String>,
+    pub date: Option<String>,
+    pub genres: Vec<String>,
+    /// 0 to 100, higher is a closer match
+    pub score: u32,
+}
+
+/// Number of candidates returned for a probed song
+pub(crate) const CANDIDATE_COUNT: usize = 5;
+
+pub(crate) trait MetadataProvider: Send + Sync {
+    fn search_release_group(
+        &self,
+        album: &str,
+        artist: &str,
+    ) -> anyhow::Result<Option<ReleaseGroupInfo>>;
+
+    fn search_recordings(
+        &self,
+        title: &str,
+        artist: Option<&str>,
+        release_group_id: Option<&str>,
+    ) -> anyhow::Result<Vec<RecordingInfo>>;
+
+    fn lookup_recording(&self, id: &str) -> anyhow::Result<Option<RecordingInfo>>;
+}
+
+static PROVIDER: LazyLock<Box<dyn MetadataProvider>> =
+    LazyLock::new(|| match env::var("JUKBX_METADATA_FIXTURES") {
+        Ok(path) => {
+            log::info!("Using metadata fixtures from {path}");
+            Box::new(FixtureProvider::load(&path).expect("Failed to load metadata fixtures"))
+        }
+        Err(_) => Box::new(MusicBrainzProvider),
+    });
+
+pub(crate) fn provider() -> &'static dyn MetadataProvider {
+    PROVIDER.as_ref()
+}
+
+/// Finds the recordings a song could be, best match first. With an album,
+/// the release group is looked up first to pick the right version; if that
+/// finds nothing the search is retried without the album.
+pub(crate) fn find_candidates(
+    provider: &dyn MetadataProvider,
+    title: &str,
+    artist: Option<&str>,
+    album: Option<&str>,
+) -> anyhow::Result<Vec<Candidate>> {
+    let mut recordings = Vec::new();
+    let mut release_group_id = None;
+
+    if let (Some(artist), Some(album)) = (artist, album) {
+        if let Some(rg) = provider.search_release_group(album, artist)? {
+            log::debug!("Found release group: {}", rg.title);
+
+            recordings = provider.search_recordings(title, Some(artist), Some(&rg.id))?;
+            release_group_id = Some(rg.id);
+        }
+
+        if recordings.is_empty() {
+            log::debug!("Retrying without album");
+        }
+    }
+
+    if recordings.is_empty() {
+        recordings = provider.search_recordings(title, artist, None)?;
+    }
+
+    let mut candidates: Vec<Candidate> = recordings
+        .into_iter()
+        .map(|rec| {
+            let score = score_recording(&rec, title, artist, album);
+            let mut candidate = to_candidate(rec, album, artist, score);
+            if candidate.release_group_id.is_none() {
+                candidate.release_group_id = release_group_id.clone();
+            }
+            candidate
+        })
+        .collect();
+    candidates.sort_by(|a, b| b.score.cmp(&a.score));
+    candidates.truncate(CANDIDATE_COUNT);
+
+    Ok(candidates)
+}
+
+/// Turns a recording into a candidate, picking the release whose title is
+/// closest to the album tag, or the earliest one without an album.
+pub(crate) fn to_candidate(
+    rec: RecordingInfo,
+    album: Option<&str>,
+    tag_artist: Option<&str>,
+    score: u32,
+) -> Candidate {
+    let release = match album {
+        Some(album) => rec.releases.iter().max_by(|a, b| {
+            similarity(&a.title, album).total_cmp(&similarity(&b.title, album))
+        }),
+        None => rec
+            .releases
+            .iter()
+            .min_by_key(|r| r.date.clone().unwrap_or("9999".to_string())),
+    };
+
+    Candidate {
+        artists: merge_artists(&rec.artists, tag_artist),
+        artist_ids: merge_artist_ids(&rec.artists),
+        release_id: release.map(|r| r.id.clone()),
+        release_group_id: release.and_then(|r| r.release_group_id.clone()),
+        release: release.map(|r| r.title.clone()),
+        date: release.and_then(|r| r.date.clone()),
+        recording_id: rec.id,
+        title: rec.title,
+        genres: rec.genres,
+        score,
+    }
+}
+
+/// How well a recording matches the file tags. The title counts the most,
+/// then the artist, then how close the best release is to the album.
+fn score_recording(
+    rec: &RecordingInfo,
+    title: &str,
+    artist: Option<&str>,
+    album: Option<&str>,
+) -> u32 {
+    let mut total = similarity(&rec.title, title) * 3.0;
+    let mut weight = 3.0;
+
+    if let Some(artist) = artist {
+        let credits = rec
+            .artists
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        total += similarity(&credits, artist) * 2.0;
+        weight += 2.0;
+    }
+
+    if let Some(album) = album {
+        total += rec
+            .releases
+            .iter()
+            .map(|r| similarity(&r.title, album))
+            .fold(0.0, f64::max);
+        weight += 1.0;
+    }
+
+    (total / weight * 100.0).round() as u32
+}
+
+/// Similarity of two strings from 0 to 1, by edit distance ignoring case.
+fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == cb { diagonal } else { diagonal + 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    1.0 - row[b.len()] as f64 / longest as f64
+}
+
+/// The credited artists of a recording, falling back to the artist from the
+/// file tags when MusicBrainz has no credits.
+pub(crate) fn merge_artists(credits: &[ArtistInfo], tag_artist: Option<&str>) -> Vec<String> {
+    let mut artists: Vec<String> = Vec::new();
+    for credit in credits {
+        if !artists.contains(&credit.name) {
+            artists.push(credit.name.clone());
+        }
+    }
+
+    if artists.is_empty() {
+        if let Some(artist) = tag_artist.filter(|a| !a.is_empty()) {
+            artists.push(artist.to_string());
+        }
+    }
+
+    artists
+}
+
+pub(crate) fn merge_artist_ids(credits: &[ArtistInfo]) -> Vec<String> {
+    let mut ids: Vec<String> = Vec::new();
+    for credit in credits {
+        if !ids.contains(&credit.id) {
+            ids.push(credit.id.clone());
+        }
+    }
+
+    ids
+}
+
+/// Looks up every song with a recording MBID again, bypassing the cache, and
+/// updates its genres and artist credits.
+pub(crate) fn refresh(db: &Database, provider: &dyn MetadataProvider) {
+    let mut count = 0;
+
+    for song in db.get_all_songs() {
+        let Some(mbid) = song.recording_mbid.as_deref() else {
+            continue;
+        };
+
+        crate::brainz_cache::invalidate("recording-id", mbid);
+        let rec = match provider.lookup_recording(mbid) {
+            Ok(Some(rec)) => rec,
+            Ok(None) => {
+                log::warn!("Recording {mbid} of {} no longer exists", song.song_path);
+                continue;
+            }
+            Err(e) => {
+                log::warn!("Failed to refresh {}: {e:?}", song.song_path);
+                continue;
+            }
+        };
+
+        log::info!("Refreshed {}", song.song_path);
+
+        let artists = merge_artists(&rec.artists, None);
+        let artist_mbids = merge_artist_ids(&rec.artists);
+        let updated = db.update_song(&song.song_path, |s| {
+            // An empty answer means MusicBrainz has nothing, not that the
+            // song has no artists or genres
+            if !artists.is_empty() {
+                s.artists = artists.into_iter().map(Cow::Owned).collect();
+                s.artist_mbids = artist_mbids.into_iter().map(Cow::Owned).collect();
+            }
+            if !rec.genres.is_empty() {
+                s.genres = rec.genres.into_iter().map(Cow::Owned).collect();
+            }
+        });
+
+        if let Some(updated) = updated {
+            crate::tags::try_write_tags(&updated);
+            count += 1;
+        }
+    }
+
+    log::info!("Refreshed metadata of {count} songs");
+}
+
+pub(crate) struct MusicBrainzProvider;
+
+static BRAINZ_MUTEX: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// Runs a MusicBrainz request, keeping to their rate limit of one request
+/// per second.
+fn rate_limited<T>(fetch: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let _guard = BRAINZ_MUTEX.lock().unwrap();
+
+    thread::sleep(Duration::from_millis(1500));
+
+    fetch()
+}
+
+/// MusicBrainz dates are partial (`2001`, `2001-05`), keep them as written.
+fn to_date_string<T: Serialize>(date: &T) -> Option<String> {
+    serde_json::to_value(date)
+        .ok()?
+        .as_str()
+        .map(String::from)
+}
+
+impl From<Recording> for RecordingInfo {
+    fn from(rec: Recording) -> Self {
+        RecordingInfo {
+            id: rec.id,
+            title: rec.title,
+            artists: rec
+                .artist_credit
+                .unwrap_or_default()
+                .into_iter()
+                .map(|c| ArtistInfo {
+                    id: c.artist.id,
+                    name: c.artist.name,
+                })
+                .collect(),
+            releases: rec
+                .releases
+                .unwrap_or_default()
+                .into_iter()
+                .map(|r| ReleaseInfo {
+                    date: r.date.as_ref().and_then(to_date_string),
+                    release_group_id: r.release_group.map(|rg| rg.id),
+                    id: r.id,
+                    title: r.title,
+                })
+                .collect(),
+            genres: rec
+                .genres
+                .unwrap_or_default()
+                .into_iter()
+                .map(|g| g.name)
+                .collect(),
+        }
+    }
+}
+
+impl From<ReleaseGroup> for ReleaseGroupInfo {
+    fn from(rg: ReleaseGroup) -> Self {
+        ReleaseGroupInfo {
+            id: rg.id,
+            title: rg.title,
+        }
+    }
+}
+
+impl MetadataProvider for MusicBrainzProvider {
+    fn search_release_group(
+        &self,
+        album: &str,
+        artist: &str,
+    ) -> anyhow::Result<Option<ReleaseGroupInfo>> {
+        let q: String = ReleaseGroupSearchQuery::query_builder()
+            .release_group(album)
+            .and()
+            .artist(artist)
+            .build();
+
+        crate::brainz_cache::get_or_fetch("release-group", &q, || {
+            rate_limited(|| {
+                log::info!("Searching MusicBrainz release groups: {q}");
+                Ok(ReleaseGroup::search(q.clone())
+                    .execute()?
+                    .entities
+                    .into_iter()
+                    .next()
+                    .map(ReleaseGroupInfo::from))
+            })
+        })
+    }
+
+    fn search_recordings(
+        &self,
+        title: &str,
+        artist: Option<&str>,
+        release_group_id: Option<&str>,
+    ) -> anyhow::Result<Vec<RecordingInfo>> {
+        let mut query = RecordingSearchQuery::query_builder();
+        query.recording(title);
+        if let Some(artist) = artist {
+            query.and().artist(artist);
+        }
+        if let Some(rgid) = release_group_id {
+            query.and().rgid(rgid);
+        }
+        let q: String = query.build();
+
+        let recordings = crate::brainz_cache::get_or_fetch("recordings", &q, || {
+            rate_limited(|| {
+                log::info!("Searching MusicBrainz recordings: {q}");
+                let found: Vec<RecordingInfo> = Recording::search(q.clone())
+                    .with_genres()
+                    .execute()?
+                    .entities
+                    .into_iter()
+                    .map(RecordingInfo::from)
+                    .collect();
+                // An empty search is cached as a miss
+                Ok(Some(found).filter(|f| !f.is_empty()))
+            })
+        })?;
+
+        Ok(recordings.unwrap_or_default())
+    }
+
+    fn lookup_recording(&self, id: &str) -> anyhow::Result<Option<RecordingInfo>> {
+        crate::brainz_cache::get_or_fetch("recording-id", id, || {
+            rate_limited(|| {
+                log::info!("Fetching MusicBrainz recording {id}");
+                let recording = Recording::fetch()
+                    .id(id)
+                    .with_artists()
+                    .with_releases()
+                    .with_genres()
+                    .execute()?;
+                Ok(Some(RecordingInfo::from(recording)))
+            })
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct ReleaseGroupFixture {
+    album: String,
+    artist: String,
+    result: Option<ReleaseGroupInfo>,
+}
+
+#[derive(Deserialize)]
+struct RecordingFixture {
+    title: String,
+    artist: Option<String>,
+    release_group_id: Option<String>,
+    #[serde(default)]
+    results: Vec<RecordingInfo>,
+}
+
+/// Answers lookups from a JSON file of canned results. Queries match when
+/// every field is equal ignoring case; anything else finds nothing.
+#[derive(Deserialize, Default)]
+pub(crate) struct FixtureProvider {
+    #[serde(default)]
+    release_groups: Vec<ReleaseGroupFixture>,
+    #[serde(default)]
+    recordings: Vec<RecordingFixture>,
+}
+
+impl FixtureProvider {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+fn eq_ignore_case(a: Option<&str>, b: Option<&str>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+impl MetadataProvider for FixtureProvider {
+    fn search_release_group(
+        &self,
+        album: &str,
+        artist: &str,
+    ) -> anyhow::Result<Option<ReleaseGroupInfo>> {
+        Ok(self
+            .release_groups
+            .iter()
+            .find(|f| {
+                eq_ignore_case(Some(&f.album), Some(album))
+                    && eq_ignore_case(Some(&f.artist), Some(artist))
+            })
+            .and_then(|f| f.result.clone()))
+    }
+
+    fn search_recordings(
+        &self,
+        title: &str,
+        artist: Option<&str>,
+        release_group_id: Option<&str>,
+    ) -> anyhow::Result<Vec<RecordingInfo>> {
+        Ok(self
+            .recordings
+            .iter()
+            .find(|f| {
+                eq_ignore_case(Some(&f.title), Some(title))
+                    && eq_ignore_case(f.artist.as_deref(), artist)
+                    && eq_ignore_case(f.release_group_id.as_deref(), release_group_id)
+            })
+            .map(|f| f.results.clone())
+            .unwrap_or_default())
+    }
+
+    fn lookup_recording(&self, id: &str) -> anyhow::Result<Option<RecordingInfo>> {
+        Ok(self
+            .recordings
+            .iter()
+            .flat_map(|f| &f.results)
+            .find(|r| r.id == id)
+            .cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artist(id: &str, name: &str) -> ArtistInfo {
+        ArtistInfo {
+            id: id.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn find_candidates_retries_without_album() {
+        let provider: FixtureProvider = serde_json::from_value(serde_json::json!({
+            "release_groups": [{
+                "album": "Discovery",
+                "artist": "Daft Punk",
+                "result": {"id": "rg-discovery", "title": "Discovery"}
+            }],
+            "recordings": [
+                {
+                    "title": "One More Time",
+                    "artist": "Daft Punk",
+                    "release_group_id": "rg-discovery",
+                    "results": []
+                },
+                {
+                    "title": "One More Time",
+                    "artist": "Daft Punk",
+                    "results": [{
+                        "id": "rec-1",
+                        "title": "One More Time",
+                        "artists": [{"id": "artist-1", "name": "Daft Punk"}],
+                        "releases": [{
+                            "id": "release-1",
+                            "title": "Discovery",
+                            "date": "2001-03-07",
+                            "release_group_id": null
+                        }],
+                        "genres": ["house"]
+                    }]
+                }
+            ]
+        }))
+        .unwrap();
+
+        let candidates =
+            find_candidates(&provider, "One More Time", Some("Daft Punk"), Some("Discovery"))
+                .unwrap();
+
+        assert_eq!(candidates.len(), 1);
+        let candidate = &candidates[0];
+        assert_eq!(candidate.recording_id, "rec-1");
+        assert_eq!(candidate.release_id.as_deref(), Some("release-1"));
+        // Kept from the album lookup, the release itself didn't name one
+        assert_eq!(candidate.release_group_id.as_deref(), Some("rg-discovery"));
+        assert_eq!(candidate.artists, vec!["Daft Punk"]);
+        assert_eq!(candidate.score, 100);
+    }
+
+    #[test]
+    fn find_candidates_finds_nothing() {
+        let provider = FixtureProvider::default();
+
+        let candidates = find_candidates(&provider, "Nothing", Some("Nobody"), None).unwrap();
+
+        assert!(candidates.is_empty());
+    }
+
+    #[test]
+    fn merge_artists_drops_joinphrases_and_duplicates() {
+        // "Alice feat. Bob & Alice", joinphrases aren't part of the credits
+        let credits = [
+            artist("id-alice", "Alice"),
+            artist("id-bob", "Bob"),
+            artist("id-alice", "Alice"),
+        ];
+
+        assert_eq!(merge_artists(&credits, Some("Alice feat. Bob")), vec!["Alice", "Bob"]);
+        assert_eq!(merge_artist_ids(&credits), vec!["id-alice", "id-bob"]);
+    }
+
+    #[test]
+    fn merge_artists_falls_back_to_tag() {
+        assert_eq!(merge_artists(&[], Some("Alice")), vec!["Alice"]);
+        assert!(merge_artists(&[], Some("")).is_empty());
+        assert!(merge_artists(&[], None).is_empty());
+        assert!(merge_artist_ids(&[]).is_empty());
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{
+    data::{Database, SongEntry},
+    macros::{escape_xml, get_header, get_query_param},
+};
+use tiny_http::{Header, Request, Response, ResponseBox};
+
+pub(crate) enum PlaylistFormat {
+    M3u8,
+    Xspf,
+    Pls,
+}
+
+struct PlaylistEntry {
+    url: String,
+    title: String,
+    artist: String,
+    album: String,
+    duration_secs: Option<u64>,
+}
+
+/// Exports the whole library, a search result (`?q=`) or an explicit list of
+/// songs (`?songs=a.mp3,b.flac`) as a playlist file that points at `/data/`.
+pub(crate) fn export(db: &Database, req: &mut Request, format: PlaylistFormat) -> ResponseBox {
+    let _ = crate::try_data_access!(db, req);
+
+    let url = req.url();
+    let songs = select_songs(db, url);
+    let base_url = get_base_url(req);
+    let token = get_query_param(url, "token");
+
+    let entries = songs
+        .iter()
+        .map(|s| PlaylistEntry {
+            url: get_data_url(&base_url, s, token.as_deref()),
+            title: s.title.to_string(),
+            artist: s.artists.join(", "),
+            album: s.album.to_string(),
+            duration_secs: s.duration_secs,
+        })
+        .collect::<Vec<_>>();
+
+    let (body, content_type, extension) = match format {
+        PlaylistFormat::M3u8 => (to_m3u8(&entries), "audio/mpegurl", "m3u8"),
+        PlaylistFormat::Xspf => (to_xspf(&entries), "application/xspf+xml", "xspf"),
+        PlaylistFormat::Pls => (to_pls(&entries), "audio/x-scpls", "pls"),
+    };
+
+    let disposition = format!("attachment; filename=\"jukbx.{extension}\"");
+
+    Response::from_string(body)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap())
+        .with_header(
+            Header::from_bytes(&b"Content-Disposition"[..], disposition.as_bytes()).unwrap(),
+        )
+        .with_status_code(200)
+        .boxed()
+}
+
+fn select_songs(db: &Database, url: &str) -> Vec<SongEntry<'static>> {
+    let all = db.get_all_songs();
+
+    if let Some(songs) = get_query_param(url, "songs") {
+        return songs
+            .split(',')
+            .filter_map(|path| all.iter().find(|s| s.song_path == path).cloned())
+            .collect();
+    }
+
+    if let Some(query) = get_query_param(url, "q") {
+        let query = query.to_uppercase();
+        return all
+            .into_iter()
+            .filter(|s| {
+                s.title.to_uppercase().contains(&query)
+                    || s.album.to_uppercase().contains(&query)
+                    || s.artists.iter().any(|a| a.to_uppercase().contains(&query))
+                    || s.genres.iter().any(|g| g.to_uppercase().contains(&query))
+            })
+            .collect();
+    }
+
+    all
+}
+
+fn get_base_url(req: &Request) -> String {
+    let scheme = get_header(req, "x-forwarded-proto").unwrap_or("http");
+    let host = get_header(req, "host").unwrap_or("localhost");
+
+    format!("{scheme}://{host}")
+}
+
+fn get_data_url(base_url: &str, song: &SongEntry, token: Option<&str>) -> String {
+    let mut url = format!(
+        "{base_url}/data/{}",
+        url_escape::encode_component(&song.song_path)
+    );
+    if let Some(token) = token {
+        url.push_str("?token=");
+        url.push_str(&url_escape::encode_component(token));
+    }
+    url
+}
+
+fn get_display_title(entry: &PlaylistEntry) -> String {
+    if entry.artist.is_empty() {
+        entry.title.clone()
+    } else {
+        format!("{} - {}", entry.artist, entry.title)
+    }
+}
+
+fn to_m3u8(entries: &[PlaylistEntry]) -> String {
+    let mut m3u = String::from("#EXTM3U\n");
+    for e in entries {
+        let duration = e.duration_secs.map(|d| d as i64).unwrap_or(-1);
+        // Line breaks in a title would end the #EXTINF line early
+        let title = get_display_title(e).replace(['\r', '\n'], " ");
+        m3u.push_str(&format!("#EXTINF:{duration},{title}\n{}\n", e.url));
+    }
+    m3u
+}
+
+fn to_xspf(entries: &[PlaylistEntry]) -> String {
+    let mut xspf = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n\
+        <trackList>\n",
+    );
+    for e in entries {
+        xspf.push_str("<track>");
+        xspf.push_str(&format!("<location>{}</location>", escape_xml(&e.url)));
+        xspf.push_str(&format!("<title>{}</title>", escape_xml(&e.title)));
+        if !e.artist.is_empty() {
+            xspf.push_str(&format!("<creator>{}</creator>", escape_xml(&e.artist)));
+        }
+        if !e.album.is_empty() {
+            xspf.push_str(&format!("<album>{}</album>", escape_xml(&e.album)));
+        }
+        if let Some(duration) = e.duration_secs {
+            xspf.push_str(&format!("<duration>{}</duration>", duration * 1000));
+        }
+        xspf.push_str("</track>\n");
+    }
+    xspf.push_str("</trackList>\n</playlist>\n");
+    xspf
+}
+
+fn to_pls(entries: &[PlaylistEntry]) -> String {
+    let mut pls = String::from("[playlist]\n");
+    for (i, e) in entries.iter().enumerate() {
+        let n = i + 1;
+        let duration = e.duration_secs.map(|d| d as i64).unwrap_or(-1);
+        let title = get_display_title(e).replace(['\r', '\n'], " ");
+        pls.push_str(&format!("File{n}={}\nTitle{n}={title}\nLength{n}={duration}\n", e.url));
+    }
+    pls.push_str(&format!("NumberOfEntries={}\nVersion=2\n", entries.len()));
+    pls
+}
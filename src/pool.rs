@@ -0,0 +1,119 @@
+//! A fixed number of threads answering requests. Requests wait in a bounded
+//! queue; when it is full they are turned away with 503 instead of piling up.
+//!
+//! Sized by `JUKBX_WORKERS` and `JUKBX_QUEUE_LIMIT`.
+//!
+//! Streams that stay open, like queue events and `/stream`, would hold a
+//! worker for good and run on threads of their own instead. Those check
+//! `is_shutting_down` and are waited for by `stop_streams`.
+
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, LazyLock, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use tiny_http::Request;
+
+const DEFAULT_WORKERS: usize = 4;
+const DEFAULT_QUEUE_LIMIT: usize = 32;
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static STREAMS: LazyLock<Mutex<Vec<JoinHandle<()>>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+
+pub(crate) struct WorkerPool {
+    sender: Option<SyncSender<Request>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+fn env_or(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&v| v > 0)
+        .unwrap_or(default)
+}
+
+impl WorkerPool {
+    pub fn from_env(handle: impl Fn(Request) + Send + Sync + 'static) -> Self {
+        Self::new(
+            env_or("JUKBX_WORKERS", DEFAULT_WORKERS),
+            env_or("JUKBX_QUEUE_LIMIT", DEFAULT_QUEUE_LIMIT),
+            handle,
+        )
+    }
+
+    pub fn new(
+        size: usize,
+        queue_limit: usize,
+        handle: impl Fn(Request) + Send + Sync + 'static,
+    ) -> Self {
+        log::info!("Starting {size} workers with a queue of {queue_limit} requests");
+
+        let (sender, receiver) = mpsc::sync_channel(queue_limit);
+        let receiver: Arc<Mutex<Receiver<Request>>> = Arc::new(Mutex::new(receiver));
+        let handle = Arc::new(handle);
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = receiver.clone();
+                let handle = handle.clone();
+                thread::spawn(move || loop {
+                    // Only hold the lock while waiting, not while handling
+                    let req = receiver.lock().unwrap().recv();
+                    match req {
+                        Ok(req) => handle(req),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+
+        WorkerPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Queues a request, handing it back if the queue is full.
+    pub fn execute(&self, req: Request) -> Result<(), Request> {
+        match self.sender.as_ref().unwrap().try_send(req) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(req)) | Err(TrySendError::Disconnected(req)) => Err(req),
+        }
+    }
+
+    /// Lets the workers finish what is queued and in flight, then stops them.
+    pub fn join(mut self) {
+        drop(self.sender.take());
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Runs a long-lived stream on a thread of its own.
+pub(crate) fn spawn_stream(stream: impl FnOnce() + Send + 'static) {
+    let mut streams = STREAMS.lock().unwrap();
+    streams.retain(|s| !s.is_finished());
+    streams.push(thread::spawn(stream));
+}
+
+pub(crate) fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/// Tells the streams to end and waits until they did.
+pub(crate) fn stop_streams() {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+
+    let streams: Vec<JoinHandle<()>> = STREAMS.lock().unwrap().drain(..).collect();
+    for stream in streams {
+        let _ = stream.join();
+    }
+}
@@ -0,0 +1,514 @@
+//! The shared "now playing" queue. Every logged in user sees and edits the
+//! same queue, and `api/queue/events` pushes each change as a Server-Sent
+//! Event so open pages stay in sync.
+//!
+//! The server only keeps time: the position of the current track follows
+//! from when it was resumed, and once it runs past the song's duration the
+//! next entry takes its place. Upcoming entries are ordered by hand or by
+//! votes, a vote moves an entry ahead of every entry with fewer votes.
+//! When the last entry ends, the auto-DJ adds a song to follow it.
+//!
+//! The queue lives in memory and starts out empty after a restart.
+
+use std::{
+    collections::BTreeSet,
+    io::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        LazyLock, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tiny_http::{Request, Response, ResponseBox};
+
+use crate::data::{Database, SongEntry};
+
+const MAX_ENTRIES: usize = 500;
+/// Shown as `added_by` on the songs the auto-DJ adds
+const AUTO_DJ_USER: &str = "auto-dj";
+const MAX_SUBSCRIBERS: usize = 64;
+const TICK: Duration = Duration::from_secs(1);
+/// Also how long it takes to notice a closed event stream
+const KEEPALIVE: Duration = Duration::from_secs(15);
+/// How long an event stream takes to end when the server shuts down
+const SHUTDOWN_CHECK: Duration = Duration::from_secs(1);
+/// How long a song whose duration can't be read plays for, so it doesn't
+/// hold up the queue
+const UNKNOWN_DURATION_MS: u64 = 3 * 60 * 1000;
+
+static QUEUE: LazyLock<Mutex<PlayQueue>> = LazyLock::new(|| Mutex::new(PlayQueue::default()));
+static SUBSCRIBERS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Serialize)]
+struct QueueEntry {
+    id: u64,
+    song: SongEntry<'static>,
+    added_by: String,
+    added_at: u64,
+    votes: BTreeSet<String>,
+}
+
+struct Current {
+    entry: QueueEntry,
+    duration_ms: Option<u64>,
+    /// The position at `resumed_at`
+    offset_ms: u64,
+    /// `None` while paused
+    resumed_at: Option<u64>,
+}
+
+impl Current {
+    fn new(entry: QueueEntry, now: u64) -> Self {
+        let duration_ms = entry.song.duration_secs.map(|s| s * 1000);
+
+        Current {
+            entry,
+            duration_ms,
+            offset_ms: 0,
+            resumed_at: Some(now),
+        }
+    }
+
+    fn position_ms(&self, now: u64) -> u64 {
+        let elapsed = self.resumed_at.map_or(0, |r| now.saturating_sub(r));
+        let position = self.offset_ms + elapsed;
+        self.duration_ms.map_or(position, |d| position.min(d))
+    }
+
+    fn is_finished(&self, now: u64) -> bool {
+        self.resumed_at.is_some()
+            && self.position_ms(now) >= self.duration_ms.unwrap_or(UNKNOWN_DURATION_MS)
+    }
+}
+
+#[derive(Serialize)]
+struct CurrentState<'a> {
+    #[serde(flatten)]
+    entry: &'a QueueEntry,
+    duration_ms: Option<u64>,
+    position_ms: u64,
+    paused: bool,
+}
+
+/// What clients get, from every endpoint and on every event.
+#[derive(Serialize)]
+struct QueueState<'a> {
+    version: u64,
+    /// When `position_ms` was taken, to follow the track in between events
+    updated_at: u64,
+    current: Option<CurrentState<'a>>,
+    entries: &'a [QueueEntry],
+}
+
+#[derive(Default)]
+struct PlayQueue {
+    next_id: u64,
+    version: u64,
+    current: Option<Current>,
+    entries: Vec<QueueEntry>,
+    subscribers: Vec<Sender<String>>,
+}
+
+impl PlayQueue {
+    fn state_json(&self, now: u64) -> String {
+        let state = QueueState {
+            version: self.version,
+            updated_at: now,
+            current: self.current.as_ref().map(|c| CurrentState {
+                entry: &c.entry,
+                duration_ms: c.duration_ms,
+                position_ms: c.position_ms(now),
+                paused: c.resumed_at.is_none(),
+            }),
+            entries: &self.entries,
+        };
+
+        serde_json::to_string(&state).unwrap()
+    }
+
+    fn push(&mut self, song: SongEntry<'static>, added_by: String, votes: BTreeSet<String>, now: u64) {
+        self.next_id += 1;
+        self.entries.push(QueueEntry {
+            id: self.next_id,
+            song,
+            added_by,
+            added_at: now / 1000,
+            votes,
+        });
+    }
+
+    /// Plays the next entry. If there is none, plays `pick`, the song the
+    /// auto-DJ picked to follow the one that ended.
+    fn advance(&mut self, pick: Option<SongEntry<'static>>, now: u64) {
+        if self.entries.is_empty() {
+            if let Some(song) = pick {
+                self.push(song, AUTO_DJ_USER.to_string(), BTreeSet::new(), now);
+            }
+        }
+
+        self.current = if self.entries.is_empty() {
+            None
+        } else {
+            Some(Current::new(self.entries.remove(0), now))
+        };
+    }
+
+    /// The current song, if it is the last one and the auto-DJ has to pick
+    /// what follows it.
+    fn get_last_song(&self) -> Option<SongEntry<'static>> {
+        let current = self.current.as_ref()?;
+        self.entries.is_empty().then(|| current.entry.song.clone())
+    }
+
+    fn position(&self, id: u64) -> Option<usize> {
+        self.entries.iter().position(|e| e.id == id)
+    }
+
+    /// Tells every subscriber about a change, dropping the ones that left.
+    fn changed(&mut self, now: u64) {
+        self.version += 1;
+
+        let json = self.state_json(now);
+        self.subscribers.retain(|s| s.send(json.clone()).is_ok());
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Songs added before durations were kept may not have one yet.
+fn fill_duration(song: &mut SongEntry<'static>) {
+    if song.duration_secs.is_none() {
+        song.duration_secs = crate::song::read_duration_secs(&song.song_path);
+    }
+}
+
+/// Picks a song to follow `song`. Called without holding the queue, the
+/// auto-DJ reads the whole library.
+fn pick_next(db: &Database, song: Option<SongEntry<'static>>) -> Option<SongEntry<'static>> {
+    let song = song?;
+    let picks = crate::autodj::pick(db, Some(&song), crate::autodj::Mode::Weighted, 1, &|_| false);
+
+    let mut pick = picks.into_iter().next()?;
+    fill_duration(&mut pick);
+    Some(pick)
+}
+
+/// Starts moving on to the next entry whenever the current one ends.
+pub(crate) fn start(db: Database) {
+    thread::spawn(move || loop {
+        thread::sleep(TICK);
+
+        let finished = {
+            let queue = QUEUE.lock().unwrap();
+            let now = now_ms();
+            match &queue.current {
+                Some(current) if current.is_finished(now) => {
+                    Some((current.entry.id, queue.get_last_song()))
+                }
+                _ => None,
+            }
+        };
+        let Some((id, last_song)) = finished else {
+            continue;
+        };
+        let pick = pick_next(&db, last_song);
+
+        let mut queue = QUEUE.lock().unwrap();
+        // Someone may have skipped it while the auto-DJ picked
+        if queue.current.as_ref().map(|c| c.entry.id) == Some(id) {
+            let now = now_ms();
+            queue.advance(pick, now);
+            queue.changed(now);
+        }
+    });
+}
+
+pub(crate) struct NowPlaying {
+    pub id: u64,
+    pub song: SongEntry<'static>,
+    pub position_ms: u64,
+    pub paused: bool,
+}
+
+pub(crate) fn now_playing() -> Option<NowPlaying> {
+    let queue = QUEUE.lock().unwrap();
+    let current = queue.current.as_ref()?;
+
+    Some(NowPlaying {
+        id: current.entry.id,
+        song: current.entry.song.clone(),
+        position_ms: current.position_ms(now_ms()),
+        paused: current.resumed_at.is_none(),
+    })
+}
+
+fn state_response(queue: &PlayQueue) -> ResponseBox {
+    Response::from_string(queue.state_json(now_ms()))
+        .with_header(
+            tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap(),
+        )
+        .with_status_code(200)
+        .boxed()
+}
+
+/// Applies a change and answers with the new state. `change` returns false
+/// if the entry it was about doesn't exist.
+fn modify(change: impl FnOnce(&mut PlayQueue, u64) -> bool) -> ResponseBox {
+    let mut queue = QUEUE.lock().unwrap();
+    let now = now_ms();
+
+    if !change(&mut queue, now) {
+        return Response::from_string("No such queue entry")
+            .with_status_code(404)
+            .boxed();
+    }
+    queue.changed(now);
+
+    state_response(&queue)
+}
+
+pub(crate) fn get_queue(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_auth!(db, req);
+
+    state_response(&QUEUE.lock().unwrap())
+}
+
+#[derive(Deserialize)]
+struct AddRequest {
+    song_path: String,
+}
+
+/// Adds a song to the end of the queue, with a vote from whoever added it.
+/// Starts playing it if nothing is.
+pub(crate) fn add(db: &Database, req: &mut Request) -> ResponseBox {
+    let user = crate::try_auth!(db, req);
+    let r: AddRequest = crate::try_json!(req);
+
+    let Some(mut song) = db.get_song_by_path(&r.song_path) else {
+        return Response::from_string("").with_status_code(404).boxed();
+    };
+    fill_duration(&mut song);
+
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.entries.len() >= MAX_ENTRIES {
+        return Response::from_string("Queue is full")
+            .with_status_code(409)
+            .boxed();
+    }
+    let now = now_ms();
+
+    queue.push(song, user.clone(), BTreeSet::from([user]), now);
+
+    if queue.current.is_none() {
+        queue.advance(None, now);
+    }
+    queue.changed(now);
+
+    state_response(&queue)
+}
+
+#[derive(Deserialize)]
+struct VoteRequest {
+    id: u64,
+    /// false takes a vote back
+    vote: bool,
+}
+
+pub(crate) fn vote(db: &Database, req: &mut Request) -> ResponseBox {
+    let user = crate::try_auth!(db, req);
+    let r: VoteRequest = crate::try_json!(req);
+
+    modify(|queue, _| {
+        let Some(index) = queue.position(r.id) else {
+            return false;
+        };
+
+        let mut entry = queue.entries.remove(index);
+        if r.vote {
+            entry.votes.insert(user);
+        } else {
+            entry.votes.remove(&user);
+        }
+
+        let votes = entry.votes.len();
+        let index = queue
+            .entries
+            .iter()
+            .position(|e| e.votes.len() < votes)
+            .unwrap_or(queue.entries.len());
+        queue.entries.insert(index, entry);
+
+        true
+    })
+}
+
+#[derive(Deserialize)]
+struct MoveRequest {
+    id: u64,
+    /// Where in the upcoming entries it goes, past the end means last
+    index: usize,
+}
+
+pub(crate) fn move_entry(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_auth!(db, req);
+    let r: MoveRequest = crate::try_json!(req);
+
+    modify(|queue, _| {
+        let Some(index) = queue.position(r.id) else {
+            return false;
+        };
+
+        let entry = queue.entries.remove(index);
+        let index = r.index.min(queue.entries.len());
+        queue.entries.insert(index, entry);
+
+        true
+    })
+}
+
+#[derive(Deserialize)]
+struct EntryRequest {
+    id: u64,
+}
+
+pub(crate) fn remove(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_auth!(db, req);
+    let r: EntryRequest = crate::try_json!(req);
+
+    modify(|queue, _| {
+        let Some(index) = queue.position(r.id) else {
+            return false;
+        };
+        queue.entries.remove(index);
+
+        true
+    })
+}
+
+/// Skips the current track. Takes its `id`, so when several people skip at
+/// once only one track is skipped.
+pub(crate) fn skip(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_auth!(db, req);
+    let r: EntryRequest = crate::try_json!(req);
+
+    let last_song = {
+        let queue = QUEUE.lock().unwrap();
+        queue
+            .get_last_song()
+            .filter(|_| queue.current.as_ref().map(|c| c.entry.id) == Some(r.id))
+    };
+    let pick = pick_next(db, last_song);
+
+    modify(|queue, now| {
+        if queue.current.as_ref().map(|c| c.entry.id) != Some(r.id) {
+            return false;
+        }
+        queue.advance(pick, now);
+
+        true
+    })
+}
+
+#[derive(Deserialize)]
+struct PlaybackRequest {
+    paused: Option<bool>,
+    position_ms: Option<u64>,
+}
+
+/// Pauses, resumes or seeks the current track.
+pub(crate) fn playback(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_auth!(db, req);
+    let r: PlaybackRequest = crate::try_json!(req);
+
+    modify(|queue, now| {
+        let Some(current) = &mut queue.current else {
+            return false;
+        };
+
+        current.offset_ms = r.position_ms.unwrap_or(current.position_ms(now));
+        if let Some(d) = current.duration_ms {
+            current.offset_ms = current.offset_ms.min(d);
+        }
+
+        let paused = r.paused.unwrap_or(current.resumed_at.is_none());
+        current.resumed_at = if paused { None } else { Some(now) };
+
+        true
+    })
+}
+
+/// Serves `api/queue/events`. Takes the request by value, the stream stays
+/// open on its own thread instead of holding up a worker.
+pub(crate) fn subscribe(db: &Database, req: Request) {
+    if crate::macros::get_optional_user(db, &req).is_none() {
+        let _ = req.respond(
+            Response::from_string("")
+                .with_header(
+                    tiny_http::Header::from_bytes(
+                        &b"WWW-Authenticate"[..],
+                        &b"Basic realm=\"my realm\""[..],
+                    )
+                    .unwrap(),
+                )
+                .with_status_code(401),
+        );
+        return;
+    }
+
+    if SUBSCRIBERS.fetch_add(1, Ordering::SeqCst) >= MAX_SUBSCRIBERS {
+        SUBSCRIBERS.fetch_sub(1, Ordering::SeqCst);
+        let _ = req.respond(Response::from_string("Too many listeners").with_status_code(503));
+        return;
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    {
+        let mut queue = QUEUE.lock().unwrap();
+        let _ = sender.send(queue.state_json(now_ms()));
+        queue.subscribers.push(sender);
+    }
+
+    crate::pool::spawn_stream(move || {
+        stream_events(req, receiver);
+        SUBSCRIBERS.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+fn stream_events(req: Request, receiver: Receiver<String>) {
+    // tiny_http would buffer a streamed body into large chunks, so the
+    // response is written by hand and flushed after every event
+    let mut writer = req.into_writer();
+    let head = "HTTP/1.1 200 OK\r\n\
+        Content-Type: text/event-stream\r\n\
+        Cache-Control: no-cache\r\n\
+        Connection: close\r\n\r\n";
+    if writer.write_all(head.as_bytes()).and_then(|_| writer.flush()).is_err() {
+        return;
+    }
+
+    let mut last_sent = Instant::now();
+    while !crate::pool::is_shutting_down() {
+        let event = match receiver.recv_timeout(SHUTDOWN_CHECK) {
+            Ok(json) => format!("event: queue\ndata: {json}\n\n"),
+            Err(RecvTimeoutError::Timeout) if last_sent.elapsed() >= KEEPALIVE => {
+                ": keepalive\n\n".to_string()
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        if writer.write_all(event.as_bytes()).and_then(|_| writer.flush()).is_err() {
+            break;
+        }
+        last_sent = Instant::now();
+    }
+}
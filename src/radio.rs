@@ -0,0 +1,399 @@
+//! `/stream`, a never ending MP3 stream for internet radio clients. It plays
+//! whatever the shared queue is playing, from the queue's position, and
+//! picks of the auto-DJ while the queue is empty. While the queue is paused
+//! the stream waits with it.
+//!
+//! Songs are sent as they are in `./songs/`, so only MP3s can be played;
+//! anything else is skipped, with auto-DJ picks filling in while the queue
+//! plays it. Clients that send `Icy-MetaData: 1` get the
+//! current song as `StreamTitle` every `ICY_METAINT` bytes.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tiny_http::{Request, Response};
+
+use crate::data::{Database, SongEntry};
+
+const ICY_METAINT: usize = 16000;
+const MAX_LISTENERS: usize = 16;
+/// How far the stream runs ahead of real time, for clients to buffer
+const LEAD_MS: f64 = 3000.0;
+const CHUNK_SIZE: usize = 4096;
+/// How far past a seek position to look for the next frame, frames are much
+/// shorter than this
+const FRAME_SEARCH_LEN: u64 = 16 * 1024;
+/// How often a waiting stream looks at the queue again
+const WAIT: Duration = Duration::from_millis(250);
+/// How many songs to ask the auto-DJ for at once
+const AUTO_DJ_PICKS: usize = 10;
+/// How long to wait before asking the auto-DJ again when it found nothing
+/// that can be streamed
+const NOTHING_PLAYABLE_WAIT: Duration = Duration::from_secs(30);
+/// Streamed songs the auto-DJ should not pick again. The stream doesn't
+/// count as a play, so the play history doesn't cover them.
+const RECENTLY_STREAMED: usize = 50;
+
+static LISTENERS: AtomicUsize = AtomicUsize::new(0);
+
+struct Track {
+    /// The queue entry, `None` for a song from the auto-DJ
+    queue_id: Option<u64>,
+    title: String,
+    /// Read a chunk at a time, from where the stream starts, past any tags
+    /// and on a frame boundary
+    file: File,
+    /// How much audio is left in `file`, up to any ID3v1 tag
+    remaining: u64,
+    bytes_per_ms: f64,
+}
+
+fn is_mp3(song: &SongEntry) -> bool {
+    song.song_path.to_lowercase().ends_with(".mp3")
+}
+
+fn get_stream_title(song: &SongEntry) -> String {
+    if song.artists.is_empty() {
+        song.title.to_string()
+    } else {
+        format!("{} - {}", song.artists.join(", "), song.title)
+    }
+}
+
+/// Where the MPEG frames of a file start and end, around ID3v2 and ID3v1
+/// tags.
+fn get_audio_range(file: &mut File) -> io::Result<(u64, u64)> {
+    let len = file.metadata()?.len();
+
+    let mut end = len;
+    if len >= 128 {
+        let mut tag = [0; 3];
+        file.seek(SeekFrom::Start(len - 128))?;
+        file.read_exact(&mut tag)?;
+        if &tag == b"TAG" {
+            end -= 128;
+        }
+    }
+
+    let mut start = 0;
+    let mut header = [0; 10];
+    file.seek(SeekFrom::Start(0))?;
+    if len >= 10 && file.read_exact(&mut header).is_ok() && header.starts_with(b"ID3") {
+        // The size is syncsafe, 7 bits per byte
+        let size = header[6..10]
+            .iter()
+            .fold(0u64, |size, b| (size << 7) | (*b & 0x7F) as u64);
+        let has_footer = header[5] & 0x10 != 0;
+        start = 10 + size + if has_footer { 10 } else { 0 };
+    }
+
+    Ok((start.min(end), end))
+}
+
+/// The first frame sync at or after `from`, or `from` if there is none
+/// close by.
+fn find_frame(file: &mut File, from: u64, end: u64) -> io::Result<u64> {
+    let mut window = Vec::new();
+    file.seek(SeekFrom::Start(from))?;
+    file.by_ref().take(FRAME_SEARCH_LEN.min(end - from)).read_to_end(&mut window)?;
+
+    let sync = (0..window.len().saturating_sub(1))
+        .find(|&i| window[i] == 0xFF && window[i + 1] & 0xE0 == 0xE0)
+        .unwrap_or(0);
+
+    Ok(from + sync as u64)
+}
+
+fn open_track(song: &SongEntry, position_ms: u64) -> io::Result<(File, u64, f64)> {
+    let mut file = File::open(format!("./songs/{}", song.song_path))?;
+    let (start, end) = get_audio_range(&mut file)?;
+
+    let duration_secs = song.duration_secs.unwrap_or(0).max(1);
+    let bytes_per_ms = (end - start) as f64 / (duration_secs * 1000) as f64;
+    let seek_to = (start + (position_ms as f64 * bytes_per_ms) as u64).min(end);
+    let offset = find_frame(&mut file, seek_to, end)?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    Ok((file, end - offset, bytes_per_ms))
+}
+
+fn load_track(song: &SongEntry, queue_id: Option<u64>, position_ms: u64) -> Option<Track> {
+    if !is_mp3(song) {
+        log::debug!("Can't stream {}, only MP3s can be", song.song_path);
+        return None;
+    }
+    song.duration_secs.filter(|&d| d > 0)?;
+
+    let (file, remaining, bytes_per_ms) = match open_track(song, position_ms) {
+        Ok(opened) => opened,
+        Err(e) => {
+            log::warn!("Failed to read {} for the stream: {e:?}", song.song_path);
+            return None;
+        }
+    };
+
+    Some(Track {
+        queue_id,
+        title: get_stream_title(song),
+        file,
+        remaining,
+        bytes_per_ms,
+    })
+}
+
+/// Keeps the stream at most `LEAD_MS` ahead of real time.
+struct Pacer {
+    started: Instant,
+    sent_ms: f64,
+}
+
+impl Pacer {
+    fn wait(&mut self) {
+        let elapsed_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+
+        // Behind after waiting on the queue: carry on from now, rather than
+        // catching up in one burst
+        if self.sent_ms < elapsed_ms {
+            self.sent_ms = elapsed_ms;
+        }
+
+        let ahead_ms = self.sent_ms - elapsed_ms;
+        if ahead_ms > LEAD_MS {
+            thread::sleep(Duration::from_secs_f64((ahead_ms - LEAD_MS) / 1000.0));
+        }
+    }
+}
+
+/// Writes audio, with a metadata block every `ICY_METAINT` bytes if the
+/// client asked for them.
+struct IcyWriter {
+    inner: Box<dyn Write + Send>,
+    metadata: bool,
+    until_metadata: usize,
+    title: String,
+    sent_title: String,
+}
+
+impl IcyWriter {
+    fn write_audio(&mut self, mut audio: &[u8]) -> io::Result<()> {
+        if !self.metadata {
+            return self.inner.write_all(audio);
+        }
+
+        while !audio.is_empty() {
+            let len = audio.len().min(self.until_metadata);
+            self.inner.write_all(&audio[..len])?;
+            audio = &audio[len..];
+
+            self.until_metadata -= len;
+            if self.until_metadata == 0 {
+                self.write_metadata()?;
+                self.until_metadata = ICY_METAINT;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// An empty block, unless the title changed since the last one.
+    fn write_metadata(&mut self) -> io::Result<()> {
+        if self.title == self.sent_title {
+            return self.inner.write_all(&[0]);
+        }
+
+        // There is no escaping in ICY metadata, a quote would end the title
+        let mut block = format!("StreamTitle='{}';", self.title.replace('\'', "\u{2019}")).into_bytes();
+        // The length is sent in 16 byte units, in one byte
+        block.truncate(255 * 16);
+        block.resize(block.len().div_ceil(16) * 16, 0);
+
+        self.inner.write_all(&[(block.len() / 16) as u8])?;
+        self.inner.write_all(&block)?;
+        self.sent_title = self.title.clone();
+
+        Ok(())
+    }
+}
+
+/// Whether the track should give way, because the queue moved on or has
+/// something to play instead of the auto-DJ. `skipped_id` is a queue entry
+/// that can't be streamed, the auto-DJ plays on through it.
+fn is_interrupted(track: &Track, skipped_id: Option<u64>) -> bool {
+    let now_playing = crate::queue::now_playing();
+    match track.queue_id {
+        Some(id) => now_playing.map_or(true, |n| n.id != id || n.paused),
+        None => now_playing.is_some_and(|n| !n.paused && Some(n.id) != skipped_id),
+    }
+}
+
+/// Sends a track in real time. Returns false if it was cut short.
+fn play(
+    out: &mut IcyWriter,
+    pacer: &mut Pacer,
+    track: &mut Track,
+    skipped_id: Option<u64>,
+) -> io::Result<bool> {
+    out.title = track.title.clone();
+
+    let mut chunk = [0; CHUNK_SIZE];
+    while track.remaining > 0 {
+        if is_interrupted(track, skipped_id) || crate::pool::is_shutting_down() {
+            return Ok(false);
+        }
+
+        let len = CHUNK_SIZE.min(track.remaining as usize);
+        // A file that can't be read any further ends the track, not the
+        // stream
+        if let Err(e) = track.file.read_exact(&mut chunk[..len]) {
+            log::warn!("Failed to read {} for the stream: {e:?}", track.title);
+            break;
+        }
+        track.remaining -= len as u64;
+
+        pacer.wait();
+        out.write_audio(&chunk[..len])?;
+        out.inner.flush()?;
+        pacer.sent_ms += len as f64 / track.bytes_per_ms;
+    }
+
+    Ok(true)
+}
+
+fn stream(db: &Database, out: &mut IcyWriter) -> io::Result<()> {
+    let mut pacer = Pacer {
+        started: Instant::now(),
+        sent_ms: 0.0,
+    };
+    let mut picks: Vec<SongEntry<'static>> = Vec::new();
+    let mut streamed: VecDeque<SongEntry<'static>> = VecDeque::new();
+    // The queue entry that was played to its end, the stream runs ahead of
+    // the queue and would otherwise start it again
+    let mut finished_id = None;
+    let mut skipped_id = None;
+    let mut next_pick_at = Instant::now();
+
+    while !crate::pool::is_shutting_down() {
+        let now_playing = crate::queue::now_playing().filter(|n| Some(n.id) != skipped_id);
+
+        let track = match now_playing {
+            Some(now_playing) => {
+                if now_playing.paused || Some(now_playing.id) == finished_id {
+                    thread::sleep(WAIT);
+                    continue;
+                }
+
+                let track = load_track(&now_playing.song, Some(now_playing.id), now_playing.position_ms);
+                if track.is_none() {
+                    log::warn!("Skipping {} on the stream, it can't be streamed", now_playing.song.song_path);
+                    skipped_id = Some(now_playing.id);
+                }
+                track
+            }
+            None => {
+                if picks.is_empty() && Instant::now() >= next_pick_at {
+                    let exclude = |s: &SongEntry| {
+                        !is_mp3(s) || streamed.iter().any(|p| p.song_path == s.song_path)
+                    };
+                    picks = crate::autodj::pick(
+                        db,
+                        streamed.back(),
+                        crate::autodj::Mode::Weighted,
+                        AUTO_DJ_PICKS,
+                        &exclude,
+                    );
+                    picks.reverse();
+
+                    if picks.is_empty() {
+                        if streamed.is_empty() {
+                            log::warn!("Nothing to stream, the library has no MP3s");
+                            next_pick_at = Instant::now() + NOTHING_PLAYABLE_WAIT;
+                        }
+                        // Everything was streamed lately, start over
+                        streamed.clear();
+                    }
+                }
+                let Some(song) = picks.pop() else {
+                    thread::sleep(WAIT);
+                    continue;
+                };
+
+                let track = load_track(&song, None, 0);
+                streamed.push_back(song);
+                if streamed.len() > RECENTLY_STREAMED {
+                    streamed.pop_front();
+                }
+                track
+            }
+        };
+        let Some(mut track) = track else {
+            thread::sleep(WAIT);
+            continue;
+        };
+
+        if play(out, &mut pacer, &mut track, skipped_id)? && track.queue_id.is_some() {
+            finished_id = track.queue_id;
+        }
+    }
+
+    Ok(())
+}
+
+/// Serves `/stream`. Like `api/queue/events` it takes the request by value
+/// and streams on a thread of its own. Only MP3s are streamed, they are
+/// sent as they are stored; songs in other formats are skipped.
+pub(crate) fn listen(db: &Database, req: Request) {
+    let listener = match crate::song::get_data_access(db, &req) {
+        Ok(listener) => listener,
+        Err(status) => {
+            let _ = req.respond(Response::from_string("").with_status_code(status));
+            return;
+        }
+    };
+
+    if LISTENERS.fetch_add(1, Ordering::SeqCst) >= MAX_LISTENERS {
+        LISTENERS.fetch_sub(1, Ordering::SeqCst);
+        let _ = req.respond(Response::from_string("Too many listeners").with_status_code(503));
+        return;
+    }
+
+    let metadata = crate::macros::get_header(&req, "Icy-MetaData") == Some("1");
+    let db = db.clone();
+
+    crate::pool::spawn_stream(move || {
+        log::info!("{listener} tuned in");
+
+        // Written by hand for the ICY headers and to flush as it goes
+        let mut writer = req.into_writer();
+        let mut head = String::from(
+            "HTTP/1.0 200 OK\r\n\
+            Content-Type: audio/mpeg\r\n\
+            icy-name: jukbx\r\n\
+            Cache-Control: no-cache\r\n\
+            Connection: close\r\n",
+        );
+        if metadata {
+            head.push_str(&format!("icy-metaint: {ICY_METAINT}\r\n"));
+        }
+        head.push_str("\r\n");
+
+        if writer.write_all(head.as_bytes()).is_ok() {
+            let mut out = IcyWriter {
+                inner: writer,
+                metadata,
+                until_metadata: ICY_METAINT,
+                title: String::new(),
+                sent_title: String::new(),
+            };
+            let _ = stream(&db, &mut out);
+        }
+
+        log::info!("{listener} tuned out");
+        LISTENERS.fetch_sub(1, Ordering::SeqCst);
+    });
+}
@@ -0,0 +1,158 @@
+//! Per-user star ratings and favorites.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tiny_http::{Request, Response, ResponseBox};
+
+use crate::{
+    data::{Database, RatingEntry, SongEntry, SongIndex},
+    require,
+};
+
+/// The `api/listSongs` body and `ETag` of each user, kept until the library
+/// or their ratings change.
+static USER_SONG_LISTS: LazyLock<Mutex<HashMap<String, (String, String)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Deserialize)]
+struct SetRatingRequest {
+    song_path: String,
+    /// 1 to 5 stars, 0 clears the rating
+    rating: Option<u8>,
+    favorite: Option<bool>,
+}
+
+/// Sets the rating, the favorite flag, or both. Fields left out keep their
+/// value.
+pub(crate) fn set_rating(db: &Database, req: &mut Request) -> ResponseBox {
+    let user = crate::try_auth!(db, req);
+    let r: SetRatingRequest = crate::try_json!(req);
+
+    require!(r.rating.map_or(true, |r| r <= 5));
+    require!(r.rating.is_some() || r.favorite.is_some());
+
+    if db.get_song_by_path(&r.song_path).is_none() {
+        return Response::from_string("").with_status_code(404).boxed();
+    }
+
+    let entry = db.set_rating(&user, &r.song_path, r.rating, r.favorite);
+
+    crate::to_json!(&entry)
+}
+
+/// Lists the user's rated and favorite songs, only favorites with
+/// `?favorites=1`.
+pub(crate) fn list_ratings(db: &Database, req: &mut Request) -> ResponseBox {
+    let user = crate::try_auth!(db, req);
+
+    let favorites_only =
+        crate::macros::get_query_param(req.url(), "favorites").as_deref() == Some("1");
+    let ratings: Vec<RatingEntry> = db
+        .get_ratings(&user)
+        .into_iter()
+        .filter(|r| !favorites_only || r.favorite)
+        .collect();
+
+    crate::to_json!(&ratings)
+}
+
+#[derive(Serialize)]
+struct UserSong<'a> {
+    #[serde(flatten)]
+    song: &'a SongEntry<'static>,
+    rating: u8,
+    favorite: bool,
+}
+
+/// The song list of `api/listSongs` with the user's rating and favorite
+/// flag on every song, and its `ETag`.
+pub(crate) fn get_user_song_list(db: &Database, index: &SongIndex, user: &str) -> (String, String) {
+    let ratings = db.get_ratings(user);
+    // Ratings are few, hashing them is much cheaper than the whole list
+    let ratings_json = serde_json::to_string(&ratings).unwrap();
+    let etag = crate::macros::get_etag(&format!("{}{ratings_json}", index.etag));
+
+    let mut lists = USER_SONG_LISTS.lock().unwrap();
+    if let Some((json, cached_etag)) = lists.get(user) {
+        if *cached_etag == etag {
+            return (json.clone(), etag);
+        }
+    }
+
+    let ratings: HashMap<&str, &RatingEntry> =
+        ratings.iter().map(|r| (r.song_path.as_str(), r)).collect();
+    let songs: Vec<UserSong> = index
+        .songs
+        .iter()
+        .map(|song| {
+            let rating = ratings.get(&song.song_path[..]);
+            UserSong {
+                song,
+                rating: rating.map_or(0, |r| r.rating),
+                favorite: rating.map_or(false, |r| r.favorite),
+            }
+        })
+        .collect();
+    let json = serde_json::to_string(&songs).unwrap();
+
+    lists.insert(user.to_string(), (json.clone(), etag.clone()));
+
+    (json, etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn song_lists_have_each_users_ratings() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = crate::data::open_test_database(dir.path());
+        for song_path in ["a.mp3", "b.mp3"] {
+            db.add_song(&SongEntry {
+                title: song_path.into(),
+                song_path: song_path.into(),
+                ..Default::default()
+            });
+        }
+        db.set_rating("alice", "a.mp3", Some(5), Some(true));
+        db.set_rating("bob", "b.mp3", Some(2), None);
+
+        let index = db.get_song_index();
+        let (alice_json, alice_etag) = get_user_song_list(&db, &index, "alice");
+        let (bob_json, bob_etag) = get_user_song_list(&db, &index, "bob");
+
+        let fields = |json: &str| -> Vec<(String, u64, bool)> {
+            serde_json::from_str::<Vec<serde_json::Value>>(json)
+                .unwrap()
+                .iter()
+                .map(|s| {
+                    (
+                        s["song_path"].as_str().unwrap().to_string(),
+                        s["rating"].as_u64().unwrap(),
+                        s["favorite"].as_bool().unwrap(),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(
+            fields(&alice_json),
+            [("a.mp3".to_string(), 5, true), ("b.mp3".to_string(), 0, false)]
+        );
+        assert_eq!(
+            fields(&bob_json),
+            [("a.mp3".to_string(), 0, false), ("b.mp3".to_string(), 2, false)]
+        );
+        assert_ne!(alice_etag, bob_etag);
+
+        // A new rating changes the list, not only the cached copy
+        db.set_rating("bob", "a.mp3", None, Some(true));
+        let (bob_json, new_bob_etag) = get_user_song_list(&db, &db.get_song_index(), "bob");
+        assert_eq!(fields(&bob_json)[0], ("a.mp3".to_string(), 0, true));
+        assert_ne!(new_bob_etag, bob_etag);
+    }
+}
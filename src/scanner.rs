@@ -0,0 +1,220 @@
+//! Imports songs from a music directory, `JUKBX_MUSIC_DIR` unless given.
+//! Files are either copied into `./songs/` or linked there, so the rest of
+//! jukbx finds them under `./songs/` either way. The original path is kept
+//! as the song's `source`, which lets a rescan skip what it already added.
+
+use std::{
+    collections::HashSet,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use lofty::{
+    file::{AudioFile, TaggedFileExt},
+    probe::Probe,
+    tag::Accessor,
+};
+use serde::{Deserialize, Serialize};
+use tiny_http::{Request, Response, ResponseBox};
+
+use crate::{
+    data::{Database, SongEntry},
+    jobs::JobKind,
+};
+
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "ogg", "oga", "opus", "m4a", "mp4", "aac", "wav"];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ImportMode {
+    /// Copy the file into `./songs/`
+    Copy,
+    /// Symlink the file into `./songs/`, leaving it where it is
+    InPlace,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ScanFailure {
+    pub path: String,
+    pub error: String,
+}
+
+#[derive(Default, Serialize)]
+pub(crate) struct ScanReport {
+    pub added: Vec<String>,
+    pub skipped: Vec<String>,
+    pub failed: Vec<ScanFailure>,
+}
+
+pub(crate) fn get_music_dir() -> Option<PathBuf> {
+    env::var("JUKBX_MUSIC_DIR").ok().map(PathBuf::from)
+}
+
+/// Adds every audio file under `dir` that isn't in the library yet.
+pub(crate) fn scan(db: &Database, dir: &Path, mode: ImportMode) -> ScanReport {
+    let mut report = ScanReport::default();
+
+    let known: HashSet<String> = db
+        .get_all_songs()
+        .into_iter()
+        .filter_map(|s| s.source.map(|s| s.into_owned()))
+        .collect();
+
+    let mut files = Vec::new();
+    collect_files(dir, &mut files, &mut report);
+    files.sort();
+
+    for file in files {
+        let Ok(source) = fs::canonicalize(&file) else {
+            report.failed.push(ScanFailure {
+                path: file.display().to_string(),
+                error: "Failed to resolve path".to_string(),
+            });
+            continue;
+        };
+        let source = source.display().to_string();
+
+        if known.contains(&source) {
+            report.skipped.push(source);
+            continue;
+        }
+
+        match import(db, Path::new(&source), mode) {
+            Ok(song_path) => {
+                log::info!("Added {source} as {song_path}");
+                report.added.push(source);
+            }
+            Err(e) => {
+                log::warn!("Failed to add {source}: {e:?}");
+                report.failed.push(ScanFailure {
+                    path: source,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    report
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>, report: &mut ScanReport) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            report.failed.push(ScanFailure {
+                path: dir.display().to_string(),
+                error: e.to_string(),
+            });
+            return;
+        }
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+
+        // Linked directories are not followed, they could loop
+        if file_type.is_dir() {
+            collect_files(&path, files, report);
+        } else if path.is_file() && get_audio_extension(&path).is_some() {
+            files.push(path);
+        }
+    }
+}
+
+fn get_audio_extension(path: &Path) -> Option<String> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+
+    AUDIO_EXTENSIONS
+        .contains(&extension.as_str())
+        .then_some(extension)
+}
+
+fn import(db: &Database, source: &Path, mode: ImportMode) -> anyhow::Result<String> {
+    let extension = get_audio_extension(source).unwrap();
+
+    let file = Probe::open(source)?.read()?;
+    let tag = file.primary_tag().or(file.first_tag());
+
+    let file_stem = source
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let title = tag
+        .and_then(|t| t.title())
+        .map(|t| t.into_owned())
+        .unwrap_or(file_stem);
+    let artist = tag.and_then(|t| t.artist()).map(|a| a.into_owned());
+    let album = tag.and_then(|t| t.album()).map(|a| a.into_owned());
+    let genre = tag.and_then(|t| t.genre()).map(|g| g.into_owned());
+    let track = tag.and_then(|t| t.track());
+
+    let song_path = crate::song::generate_song_path(&extension);
+    let dest = format!("./songs/{song_path}");
+    match mode {
+        ImportMode::Copy => {
+            fs::copy(source, &dest)?;
+        }
+        ImportMode::InPlace => {
+            std::os::unix::fs::symlink(source, &dest)?;
+        }
+    }
+
+    let song = SongEntry {
+        title: title.into(),
+        album: album.unwrap_or_default().into(),
+        track,
+        duration_secs: Some(file.properties().duration().as_secs()),
+        artists: artist.into_iter().map(|a| a.into()).collect(),
+        genres: genre.into_iter().map(|g| g.into()).collect(),
+        song_path: song_path.clone().into(),
+        source: Some(source.display().to_string().into()),
+        ..Default::default()
+    };
+
+    db.add_song(&song);
+
+    // Lyrics in an .lrc file next to the song win over embedded ones
+    match fs::read_to_string(source.with_extension("lrc")) {
+        Ok(lyrics) => crate::lyrics::set_lyrics(&song_path, &lyrics)?,
+        Err(_) => crate::lyrics::store_embedded(&song_path),
+    }
+    crate::jobs::enqueue_song(db, &song_path);
+
+    Ok(song_path)
+}
+
+#[derive(Deserialize)]
+struct ScanRequest {
+    #[serde(default)]
+    in_place: bool,
+}
+
+#[derive(Serialize)]
+struct ScanResponse {
+    job: u64,
+}
+
+/// Queues a scan of the configured music directory and returns the job,
+/// which lists in `api/jobs` as done with the scan report as its `result`
+/// once the scan finished. Only admins may start a scan.
+pub(crate) fn scan_library(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_admin!(db, req);
+    let r: ScanRequest = crate::try_json!(req);
+
+    let Some(dir) = get_music_dir() else {
+        return Response::from_string("JUKBX_MUSIC_DIR is not set")
+            .with_status_code(400)
+            .boxed();
+    };
+
+    let kind = if r.in_place {
+        JobKind::ScanInPlace
+    } else {
+        JobKind::Scan
+    };
+    let job = db.add_job(kind, &dir.display().to_string(), crate::jobs::now());
+
+    crate::to_json!(&ScanResponse { job: job.id })
+}
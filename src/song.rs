@@ -1,14 +1,16 @@
-use crate::{data::Database, require};
+use crate::{
+    data::Database,
+    jobs::JobKind,
+    metadata::{self, Candidate, MetadataProvider},
+    require,
+};
 use base64::{prelude::BASE64_STANDARD, Engine};
-use lofty::{file::TaggedFileExt, probe::Probe, tag::Accessor};
-use log::debug;
-use musicbrainz_rs_nova::{
-    entity::{
-        recording::{Recording, RecordingSearchQuery},
-        release_group::{ReleaseGroup, ReleaseGroupSearchQuery},
-    },
-    Browse, Search,
+use lofty::{
+    file::{AudioFile, TaggedFileExt},
+    probe::Probe,
+    tag::Accessor,
 };
+use log::debug;
 use petname::Generator;
 use serde::{Deserialize, Serialize};
 use std::{
@@ -19,11 +21,12 @@ use std::{
     io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom},
     iter::Once,
     ops::Range,
+    path::Path,
     sync::{LazyLock, Mutex},
     thread,
     time::Duration,
 };
-use tiny_http::{Header, Request, Response, ResponseBox};
+use tiny_http::{Header, Method, Request, Response, ResponseBox};
 
 #[derive(Deserialize)]
 struct ListDataRequest {}
@@ -34,16 +37,35 @@ struct Data {
     value: String,
 }
 
+/// Sends the whole library. Also answers `GET` so browsers can revalidate
+/// it with the `ETag`.
 pub(crate) fn list(db: &Database, req: &mut Request) -> ResponseBox {
-    let r: ListDataRequest = crate::try_json!(req);
+    if *req.method() != Method::Get {
+        let r: ListDataRequest = crate::try_json!(req);
+    }
+
+    let index = db.get_song_index();
 
-    let json = db.get_all_json();
+    // Logged in users also get their ratings, in a body cached per user
+    let (json, etag) = match crate::macros::get_optional_user(db, req) {
+        Some(user) => crate::ratings::get_user_song_list(db, &index, &user),
+        None => (index.json.clone(), index.etag.clone()),
+    };
+
+    let etag_header = Header::from_bytes(&b"ETag"[..], etag.as_bytes()).unwrap();
+    let vary = Header::from_bytes(&b"Vary"[..], &b"Authorization"[..]).unwrap();
+
+    if crate::macros::get_header(req, "If-None-Match") == Some(etag.as_str()) {
+        return Response::empty(304).with_header(etag_header).with_header(vary).boxed();
+    }
 
     Response::from_string(json)
         .with_status_code(200)
         .with_header(
             tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap(),
         )
+        .with_header(etag_header)
+        .with_header(vary)
         .boxed()
 }
 
@@ -79,10 +101,134 @@ pub(crate) fn get_audio_page(db: &Database, req: &mut Request) -> ResponseBox {
     }
 }
 
+pub(crate) fn read_duration_secs(song_path: &str) -> Option<u64> {
+    let file = Probe::open(format!("./songs/{song_path}"))
+        .and_then(|p| p.read())
+        .ok()?;
+
+    Some(file.properties().duration().as_secs())
+}
+
+/// Stores the duration of songs added before durations were kept.
+pub(crate) fn backfill_durations(db: &Database) {
+    let mut count = 0;
+
+    for song in db.get_all_songs() {
+        if song.duration_secs.is_some() {
+            continue;
+        }
+        let Some(duration_secs) = read_duration_secs(&song.song_path) else {
+            continue;
+        };
+        if db.update_song(&song.song_path, |s| s.duration_secs = Some(duration_secs)).is_some() {
+            count += 1;
+        }
+    }
+
+    if count > 0 {
+        log::info!("Stored the duration of {count} songs");
+    }
+}
+
+/// Path of a file stored next to a song, like its waveform peaks.
+pub(crate) fn get_sidecar_path(song_path: &str, extension: &str) -> String {
+    format!("./songs/{song_path}.{extension}")
+}
+
 fn get_audio_page_html(song: crate::data::SongEntry<'_>) -> String {
+    let song_path = url_escape::encode_component(&song.song_path);
+
     format!(
-        "<html><audio controls src=\"/data/{}\"></audio>",
-        song.song_path
+        r##"<html>
+<meta charset="utf-8" />
+<meta name="viewport" content="width=device-width, initial-scale=1" />
+<title>{title}</title>
+<style>
+  body {{ font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif; padding: 2em; }}
+  #waveform {{ width: 100%; height: 6em; cursor: pointer; }}
+  #play {{ font-size: 1.5em; }}
+  #lyrics {{ white-space: pre-wrap; line-height: 1.6; }}
+  #lyrics p {{ margin: 0; color: #aaa; }}
+  #lyrics p.current {{ color: #000; font-weight: bold; }}
+</style>
+<h2>{title}</h2>
+<h3>{artists}</h3>
+<button id="play">play</button> <span id="time"></span>
+<canvas id="waveform"></canvas>
+<audio id="audio" preload="metadata" src="/data/{song_path}"></audio>
+<div id="lyrics"></div>
+<script>
+  let audio = document.getElementById("audio");
+  let canvas = document.getElementById("waveform");
+  let play = document.getElementById("play");
+  let peaks = [];
+
+  function draw() {{
+    canvas.width = canvas.clientWidth;
+    canvas.height = canvas.clientHeight;
+    let ctx = canvas.getContext("2d");
+    let played = audio.duration ? audio.currentTime / audio.duration : 0;
+    let barWidth = canvas.width / Math.max(peaks.length, 1);
+    peaks.forEach((p, i) => {{
+      let height = Math.max(1, p / 255 * canvas.height);
+      ctx.fillStyle = i / peaks.length < played ? "#333" : "#aaa";
+      ctx.fillRect(i * barWidth, (canvas.height - height) / 2, Math.max(barWidth - 1, 1), height);
+    }});
+    let time = t => Math.floor(t / 60) + ":" + String(Math.floor(t % 60)).padStart(2, "0");
+    document.getElementById("time").innerText = audio.duration ? time(audio.currentTime) + " / " + time(audio.duration) : "";
+  }}
+
+  let lyricLines = [];
+  function showLyricLine() {{
+    let time = audio.currentTime * 1000;
+    let current = lyricLines.filter(l => l.time_ms <= time).pop();
+    lyricLines.forEach(l => {{
+      if (l == current && !l.element.classList.contains("current")) {{
+        l.element.scrollIntoView({{ block: "center", behavior: "smooth" }});
+      }}
+      l.element.classList.toggle("current", l == current);
+    }});
+  }}
+
+  play.onclick = () => audio.paused ? audio.play() : audio.pause();
+  audio.onplay = () => play.innerText = "pause";
+  audio.onpause = () => play.innerText = "play";
+  audio.ontimeupdate = () => {{ draw(); showLyricLine(); }};
+  audio.onloadedmetadata = draw;
+  window.onresize = draw;
+  canvas.onclick = e => {{
+    if (audio.duration) {{
+      audio.currentTime = e.offsetX / canvas.clientWidth * audio.duration;
+    }}
+  }};
+
+  fetch("/api/waveform/{song_path}")
+    .then(r => r.ok ? r.json() : {{ peaks: [] }})
+    .then(w => {{ peaks = w.peaks; draw(); }});
+
+  fetch("/api/lyrics/{song_path}")
+    .then(r => r.ok ? r.json() : null)
+    .then(l => {{
+      let lyrics = document.getElementById("lyrics");
+      if (l == null) {{
+        return;
+      }}
+      if (!l.synced) {{
+        lyrics.innerText = l.text;
+        return;
+      }}
+      lyricLines = l.lines;
+      lyricLines.forEach(line => {{
+        line.element = document.createElement("p");
+        line.element.innerText = line.text || "\u00a0";
+        line.element.onclick = () => audio.currentTime = line.time_ms / 1000;
+        lyrics.appendChild(line.element);
+      }});
+    }});
+</script>
+</html>"##,
+        title = crate::macros::escape_xml(&song.title),
+        artists = crate::macros::escape_xml(&song.artists.join(", ")),
     )
 }
 
@@ -107,6 +253,10 @@ fn parse_range_header(header: &Header) -> anyhow::Result<HttpRange> {
         let from = from.parse()?;
 
         Ok(HttpRange::Open { start: from })
+    } else if from.is_empty() {
+        let to = to.parse()?;
+
+        Ok(HttpRange::Negative { value: to })
     } else {
         let from = from.parse()?;
         let to = to.parse()?;
@@ -118,30 +268,43 @@ fn parse_range_header(header: &Header) -> anyhow::Result<HttpRange> {
     }
 }
 
-pub(crate) fn get_audio_data(db: &Database, req: &mut Request) -> ResponseBox {
-    let Some(header) = req
-        .headers()
-        .iter()
-        .find(|h| h.field == "x-real-ip".parse().unwrap())
-    else {
+/// Checks whether the request may read audio data, either through a share
+/// token in the query string or a whitelisted IP. Returns who was let in.
+pub(crate) fn get_data_access(db: &Database, req: &Request) -> Result<String, u16> {
+    if let Some(token) = crate::macros::get_query_param(req.url(), "token") {
+        // Named after whoever shared it, the token must not end up in the
+        // play history
+        if let Some(user) = db.get_share_token_user(&token) {
+            debug!("Allowed share token of {user}");
+            return Ok(format!("share:{user}"));
+        }
+    }
+
+    let Some(ip) = crate::macros::get_header(req, "x-real-ip") else {
         log::warn!("No IP header found");
-        return Response::from_string("").with_status_code(400).boxed();
+        return Err(400);
     };
 
-    let range_header = req
-        .headers()
-        .iter()
-        .find(|h| h.field == "Range".parse().unwrap())
-        .map(|h| parse_range_header(h));
-
-    let ip = header.value.as_str();
-    if !db.is_allowed(&ip) {
+    if !db.is_allowed(ip) {
         debug!("IP {ip} is not allowed");
-        return Response::from_string("").with_status_code(403).boxed();
+        return Err(403);
     }
 
     debug!("Allowed {ip}");
 
+    Ok(ip.to_string())
+}
+
+/// Whether a request for a song is someone starting to play it, rather than
+/// seeking or a player probing the file with a short range.
+fn is_play_start(req: &Request) -> bool {
+    *req.method() == Method::Get
+        && crate::macros::get_header(req, "Range").map_or(true, |r| r.trim() == "bytes=0-")
+}
+
+pub(crate) fn get_audio_data(db: &Database, req: &mut Request) -> ResponseBox {
+    let listener = crate::try_data_access!(db, req);
+
     let url = req.url();
     let mut components = url.split('/');
     components.next();
@@ -149,10 +312,67 @@ pub(crate) fn get_audio_data(db: &Database, req: &mut Request) -> ResponseBox {
     let Some(file) = components.next() else {
         return Response::from_string("").with_status_code(404).boxed();
     };
+    let file = url_escape::decode(file.split('?').next().unwrap_or(file)).into_owned();
 
-    let Ok(mut file) = File::open(format!("./songs/{}", file)) else {
+    // Only songs in the library, a decoded `%2F` could point anywhere else
+    let Some(song) = db.get_song_by_path(&file) else {
         return Response::from_string("").with_status_code(404).boxed();
     };
+    let file = song.song_path.into_owned();
+
+    if let Some(format) = crate::macros::get_query_param(url, "format") {
+        let Some(format) = crate::transcode::TranscodeFormat::parse(&format) else {
+            return Response::from_string("Unsupported format")
+                .with_status_code(400)
+                .boxed();
+        };
+        let bitrate = crate::macros::get_query_param(url, "bitrate")
+            .and_then(|b| b.parse::<u32>().ok())
+            .unwrap_or(crate::transcode::DEFAULT_BITRATE_KBPS);
+        require!((16..=256).contains(&bitrate));
+
+        if !Path::new(&format!("./songs/{}", file)).is_file() {
+            return Response::from_string("").with_status_code(404).boxed();
+        }
+
+        let path = match crate::transcode::get_transcoded(&file, format, bitrate) {
+            Ok(path) => path,
+            Err(e) => {
+                log::error!("Failed to transcode {file}: {e:?}");
+                return Response::from_string("").with_status_code(500).boxed();
+            }
+        };
+
+        let Ok(transcoded) = File::open(path) else {
+            return Response::from_string("").with_status_code(500).boxed();
+        };
+
+        if is_play_start(req) {
+            crate::history::record_play(db, &file, &listener, "data");
+        }
+
+        return serve_file(req, transcoded);
+    }
+
+    let Ok(song_file) = File::open(format!("./songs/{}", file)) else {
+        return Response::from_string("").with_status_code(404).boxed();
+    };
+
+    if is_play_start(req) {
+        crate::history::record_play(db, &file, &listener, "data");
+    }
+
+    serve_file(req, song_file)
+}
+
+/// Responds with the contents of `file`, honouring a `Range` header if the
+/// client sent one.
+pub(crate) fn serve_file(req: &Request, mut file: File) -> ResponseBox {
+    let range_header = req
+        .headers()
+        .iter()
+        .find(|h| h.field == "Range".parse().unwrap())
+        .map(|h| parse_range_header(h));
 
     let file_size = file.metadata().ok().map(|v| v.len() as usize);
 
@@ -166,9 +386,13 @@ pub(crate) fn get_audio_data(db: &Database, req: &mut Request) -> ResponseBox {
 
         let mut headers = vec![];
 
-        match range {
+        let start = match range {
             HttpRange::Inclusive { start, end } => {
-                let len = end - start;
+                let end = end.min((file_size as u64).saturating_sub(1));
+                if start > end {
+                    return Response::from_string("").with_status_code(416).boxed();
+                }
+                let len = end - start + 1;
                 let len_value = format!("{len}");
                 let content_range = format!("bytes {start}-{end}/{file_size}");
                 headers.push(
@@ -192,29 +416,30 @@ pub(crate) fn get_audio_data(db: &Database, req: &mut Request) -> ResponseBox {
                 )
                 .boxed();
             }
-            HttpRange::Open { start } => {
-                let len = file_size as u64 - start;
-                let len_value = format!("{len}");
-                let content_range = format!("bytes {start}-{file_size}/{file_size}");
-                headers.push(
-                    Header::from_bytes(&b"content-length"[..], len_value.as_bytes()).unwrap(),
-                );
-                headers.push(
-                    Header::from_bytes(&b"content-range"[..], content_range.as_bytes()).unwrap(),
-                );
-
-                let Ok(_) = file.seek(SeekFrom::Start(start)) else {
-                    return Response::from_string("").with_status_code(500).boxed();
-                };
+            HttpRange::Open { start } => start,
+            // The last `value` bytes
+            HttpRange::Negative { value } => (file_size as u64).saturating_sub(value.max(0) as u64),
+        };
 
-                let reader = BufReader::new(file);
-                return Response::new(tiny_http::StatusCode(206), headers, reader, None, None)
-                    .boxed();
-            }
-            HttpRange::Negative { value } => todo!(),
+        if start >= file_size as u64 {
+            return Response::from_string("").with_status_code(416).boxed();
         }
+        let len = file_size as u64 - start;
+        let len_value = format!("{len}");
+        let content_range = format!("bytes {start}-{}/{file_size}", file_size - 1);
+        headers.push(
+            Header::from_bytes(&b"content-length"[..], len_value.as_bytes()).unwrap(),
+        );
+        headers.push(
+            Header::from_bytes(&b"content-range"[..], content_range.as_bytes()).unwrap(),
+        );
+
+        let Ok(_) = file.seek(SeekFrom::Start(start)) else {
+            return Response::from_string("").with_status_code(500).boxed();
+        };
 
-        todo!()
+        let reader = BufReader::new(file);
+        Response::new(tiny_http::StatusCode(206), headers, reader, None, None).boxed()
     } else {
         Response::new(
             tiny_http::StatusCode(200),
@@ -232,156 +457,118 @@ struct ProbeSongRequest {
     song_data_base64: String,
 }
 
+#[derive(Default, Serialize)]
+struct FileTags {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    track: Option<u32>,
+}
+
+/// The fields of the best candidate, or of the file tags when nothing
+/// matched, so the upload form can be filled in right away.
 #[derive(Default, Serialize)]
 struct ProbeSongResponse {
     title: Option<String>,
     artists: Vec<String>,
     album: Option<String>,
+    track: Option<u32>,
     genres: Vec<String>,
+    recording_mbid: Option<String>,
+    release_group_mbid: Option<String>,
+    artist_mbids: Vec<String>,
+    tags: FileTags,
+    candidates: Vec<Candidate>,
 }
 
-fn get_metadata(song_data_base64: String) -> anyhow::Result<ProbeSongResponse> {
+fn get_metadata(
+    provider: &dyn MetadataProvider,
+    song_data_base64: String,
+) -> anyhow::Result<ProbeSongResponse> {
     let data = BASE64_STANDARD.decode(song_data_base64)?;
 
-    let probe = Probe::new(Cursor::new(data)).guess_file_type()?;
+    let probe = Probe::new(Cursor::new(&data[..])).guess_file_type()?;
     let file = probe.read()?;
-    let t = file.primary_tag();
-    let Some(tag) = t else {
-        return Err(anyhow::anyhow!("No tags found"));
-    };
+    let tag = file.primary_tag();
 
-    let title = tag.title().ok_or(anyhow::anyhow!("No title found"))?;
-    let artist = tag.artist();
-    let album = tag.album();
-
-    let recordingz = get_musicbrainz_metadata(&title, artist.clone(), album.clone());
-    if let Ok((rec, rg)) = recordingz {
-        let album = if let Some(rg) = rg {
-            Some(rg.title)
-        } else {
-            album.map(|a| a.to_string()).or(rec
-                .releases
-                .and_then(|r| r.into_iter().next().map(|r| r.title)))
-        };
+    let Some(title) = tag.and_then(|t| t.title()) else {
+        debug!("No title tag, identifying by fingerprint");
+        return identify_by_fingerprint(provider, data);
+    };
+    let tag = tag.unwrap();
+    let tags = FileTags {
+        title: Some(title.into_owned()),
+        artist: tag.artist().map(|a| a.into_owned()),
+        album: tag.album().map(|a| a.into_owned()),
+        track: tag.track(),
+    };
 
-        let mut artists = Vec::new();
-        if let Some(artist) = artist {
-            artists.push(artist.to_string());
+    let candidates = match metadata::find_candidates(
+        provider,
+        tags.title.as_deref().unwrap(),
+        tags.artist.as_deref(),
+        tags.album.as_deref(),
+    ) {
+        Ok(candidates) => candidates,
+        Err(e) => {
+            log::warn!("Failed to find candidates: {e:?}");
+            vec![]
         }
+    };
 
-        return Ok(ProbeSongResponse {
-            title: Some(rec.title),
-            artists: rec
-                .artist_credit
-                .map(|a| a.into_iter().map(|c| c.artist.name).collect())
-                .unwrap_or(artists),
-            album: album,
-            genres: rec
-                .genres
-                .map(|g| g.into_iter().map(|g| g.name).collect())
-                .unwrap_or(vec![]),
-        });
-    }
-
-    return Ok(ProbeSongResponse {
-        title: Some(title.into_owned()),
-        album: album.map(|a| a.into_owned()),
-        artists: artist.map(|a| vec![a.into_owned()]).unwrap_or(vec![]),
-        ..Default::default()
-    });
+    Ok(response_from_candidates(tags, candidates))
 }
 
-static BRAINZ_MUTEX: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
-
-fn get_musicbrainz_metadata<'a>(
-    title: &str,
-    artist: Option<Cow<'a, str>>,
-    album: Option<Cow<'a, str>>,
-) -> anyhow::Result<(Recording, Option<ReleaseGroup>)> {
-    let guard = BRAINZ_MUTEX.lock().unwrap();
-
-    println!(
-        "Fetching MusicBrainz data using title: {title}, artist: {artist:?}, album: {album:?}"
-    );
-
-    thread::sleep(Duration::from_millis(1500));
-
-    match (artist, album) {
-        (Some(artist), Some(album)) => {
-            let q: String = ReleaseGroupSearchQuery::query_builder()
-                .release_group(&album)
-                .and()
-                .artist(&artist)
-                .build();
-
-            let release_group = ReleaseGroup::search(q)
-                .execute()?
-                .entities
-                .into_iter()
-                .next();
-
-            if let Some(rg) = &release_group {
-                println!("Found release group: {}", rg.title);
-
-                thread::sleep(Duration::from_millis(1500));
-
-                let q: String = RecordingSearchQuery::query_builder()
-                    .recording(title)
-                    .and()
-                    .artist(&artist)
-                    .and()
-                    .rgid(&rg.id)
-                    .build();
-
-                let recording: Option<Recording> = Recording::search(q)
-                    .with_genres()
-                    .execute()?
-                    .entities
-                    .into_iter()
-                    .next();
-                let recording = recording.ok_or(anyhow::anyhow!("Failed to find recording"))?;
-
-                return Ok((recording, release_group));
-            }
+fn response_from_candidates(tags: FileTags, candidates: Vec<Candidate>) -> ProbeSongResponse {
+    let Some(best) = candidates.first() else {
+        return ProbeSongResponse {
+            title: tags.title.clone(),
+            album: tags.album.clone(),
+            track: tags.track,
+            artists: tags.artist.iter().cloned().collect(),
+            tags,
+            ..Default::default()
+        };
+    };
 
-            println!("Retrying without album");
+    ProbeSongResponse {
+        title: Some(best.title.clone()),
+        artists: best.artists.clone(),
+        album: tags.album.clone().or(best.release.clone()),
+        track: tags.track,
+        genres: best.genres.clone(),
+        recording_mbid: Some(best.recording_id.clone()),
+        release_group_mbid: best.release_group_id.clone(),
+        artist_mbids: best.artist_ids.clone(),
+        tags,
+        candidates,
+    }
+}
 
-            // Try again without album
-            drop(guard);
-            return get_musicbrainz_metadata(title, Some(artist), None);
+/// For files without tags, asks AcoustID which recording they are.
+fn identify_by_fingerprint(
+    provider: &dyn MetadataProvider,
+    data: Vec<u8>,
+) -> anyhow::Result<ProbeSongResponse> {
+    let fingerprint = crate::fingerprint::compute(data)?;
+    let recording_ids = crate::fingerprint::lookup(&fingerprint)?;
+
+    let mut candidates = Vec::new();
+    for (id, score) in recording_ids {
+        if candidates.len() == metadata::CANDIDATE_COUNT {
+            break;
         }
-        (Some(artist), _) => {
-            let q: String = RecordingSearchQuery::query_builder()
-                .recording(title)
-                .and()
-                .artist(&artist)
-                .build();
-            let recording = Recording::search(q)
-                .with_genres()
-                .execute()?
-                .entities
-                .into_iter()
-                .next();
-
-            let recording = recording.ok_or(anyhow::anyhow!("Failed to find recording"))?;
-
-            return Ok((recording, None));
-        }
-        _ => {
-            let q: String = RecordingSearchQuery::query_builder()
-                .recording(title)
-                .build();
-            let recording = Recording::search(q)
-                .with_genres()
-                .execute()?
-                .entities
-                .into_iter()
-                .next();
-            let recording = recording.ok_or(anyhow::anyhow!("Failed to find recording"))?;
-
-            return Ok((recording, None));
+        if let Some(rec) = provider.lookup_recording(&id)? {
+            let score = (score * 100.0).round() as u32;
+            candidates.push(metadata::to_candidate(rec, None, None, score));
         }
     }
+
+    if candidates.is_empty() {
+        return Err(anyhow::anyhow!("No title found and the fingerprint matched nothing"));
+    }
+
+    Ok(response_from_candidates(FileTags::default(), candidates))
 }
 
 pub(crate) fn probe(db: &Database, req: &mut Request) -> ResponseBox {
@@ -390,7 +577,7 @@ pub(crate) fn probe(db: &Database, req: &mut Request) -> ResponseBox {
 
     require!(r.song_data_base64.len() < 1024 * 1024 * 130);
 
-    match get_metadata(r.song_data_base64) {
+    match get_metadata(metadata::provider(), r.song_data_base64) {
         Ok(md) => {
             return crate::to_json!(&md);
         }
@@ -402,6 +589,56 @@ pub(crate) fn probe(db: &Database, req: &mut Request) -> ResponseBox {
     }
 }
 
+#[derive(Deserialize)]
+struct ApplyCandidateRequest {
+    recording_id: String,
+    release_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ApplyCandidateResponse {
+    title: String,
+    artists: Vec<String>,
+    album: Option<String>,
+    genres: Vec<String>,
+    recording_mbid: String,
+    release_group_mbid: Option<String>,
+    artist_mbids: Vec<String>,
+}
+
+/// Fetches the full recording of the candidate the uploader picked, returning
+/// the fields for the upload form.
+pub(crate) fn apply_candidate(db: &Database, req: &mut Request) -> ResponseBox {
+    let username = crate::try_auth!(db, req);
+    let r: ApplyCandidateRequest = crate::try_json!(req);
+
+    let rec = match metadata::provider().lookup_recording(&r.recording_id) {
+        Ok(Some(rec)) => rec,
+        Ok(None) => return Response::from_string("").with_status_code(404).boxed(),
+        Err(e) => {
+            return Response::from_string(format!("{e:?}"))
+                .with_status_code(400)
+                .boxed();
+        }
+    };
+
+    let release = rec
+        .releases
+        .iter()
+        .find(|rel| Some(&rel.id) == r.release_id.as_ref())
+        .or(rec.releases.first());
+
+    crate::to_json!(&ApplyCandidateResponse {
+        artists: metadata::merge_artists(&rec.artists, None),
+        artist_mbids: metadata::merge_artist_ids(&rec.artists),
+        album: release.map(|rel| rel.title.clone()),
+        release_group_mbid: release.and_then(|rel| rel.release_group_id.clone()),
+        title: rec.title,
+        genres: rec.genres,
+        recording_mbid: rec.id,
+    })
+}
+
 #[derive(Deserialize)]
 struct AddSongRequest {
     song_data_filename: String,
@@ -410,10 +647,33 @@ struct AddSongRequest {
     artists: Vec<String>,
     album: String,
     genres: Vec<String>,
+    #[serde(default)]
+    track: Option<u32>,
+    #[serde(default)]
+    recording_mbid: Option<String>,
+    #[serde(default)]
+    release_group_mbid: Option<String>,
+    #[serde(default)]
+    artist_mbids: Vec<String>,
 }
 
+/// The song is playable right away, the rest of its processing is done by
+/// the listed jobs.
 #[derive(Serialize)]
-struct AddSongResponse {}
+struct AddSongResponse {
+    song_path: String,
+    jobs: Vec<u64>,
+}
+
+/// A new name for a song file in `./songs/`, keeping its extension.
+pub(crate) fn generate_song_path(extension: &str) -> String {
+    let mut rng = rand::thread_rng();
+    let name = petname::Petnames::small()
+        .generate(&mut rng, 7, "-")
+        .expect("no names");
+
+    format!("{}.{}", name, extension)
+}
 
 pub(crate) fn add(db: &Database, req: &mut Request) -> ResponseBox {
     let username = crate::try_auth!(db, req);
@@ -424,21 +684,73 @@ pub(crate) fn add(db: &Database, req: &mut Request) -> ResponseBox {
     require!(r.song_data_base64.len() < 1024 * 1024 * 130);
 
     let data = BASE64_STANDARD.decode(r.song_data_base64).unwrap();
-    let mut rng = rand::thread_rng();
-    let name = petname::Petnames::small()
-        .generate(&mut rng, 7, "-")
-        .expect("no names");
-    let path = format!("./songs/{}.{}", name, extension);
+    let song_path = generate_song_path(extension);
     //let path = format!("./{}", r.song_file_name);
-    fs::write(&path, data).unwrap();
+    fs::write(format!("./songs/{song_path}"), data).unwrap();
 
-    db.add_song(&crate::data::SongEntry {
+    let song = crate::data::SongEntry {
         title: r.title.into(),
         album: r.album.into(),
+        track: r.track,
         artists: r.artists.into_iter().map(|g| g.into()).collect(),
         genres: r.genres.into_iter().map(|g| g.into()).collect(),
-        song_path: format!("{}.{}", name, extension).into(),
-    });
+        song_path: song_path.into(),
+        recording_mbid: r.recording_mbid.filter(|m| !m.is_empty()).map(|m| m.into()),
+        release_group_mbid: r.release_group_mbid.filter(|m| !m.is_empty()).map(|m| m.into()),
+        artist_mbids: r.artist_mbids.into_iter().map(|m| m.into()).collect(),
+        ..Default::default()
+    };
+
+    db.add_song(&song);
+
+    // Reading and writing the file is left to jobs, so the upload returns
+    // as soon as the song is stored
+    let now = crate::jobs::now();
+    let mut jobs: Vec<u64> = [JobKind::Duration, JobKind::Tags, JobKind::Lyrics]
+        .into_iter()
+        .map(|kind| db.add_job(kind, &song.song_path, now).id)
+        .collect();
+    jobs.extend(crate::jobs::enqueue_song(db, &song.song_path));
+
+    crate::to_json!(&AddSongResponse {
+        song_path: song.song_path.to_string(),
+        jobs,
+    })
+}
+
+#[derive(Deserialize)]
+struct EditSongRequest {
+    song_path: String,
+    title: String,
+    artists: Vec<String>,
+    album: String,
+    genres: Vec<String>,
+    #[serde(default)]
+    track: Option<u32>,
+}
+
+/// Corrects the metadata of a song, in `songs.csv` and in the file's tags.
+pub(crate) fn edit(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_auth!(db, req);
+    let r: EditSongRequest = crate::try_json!(req);
+
+    require!(!r.title.trim().is_empty());
+
+    let Some(song) = db.get_song_by_path(&r.song_path) else {
+        return Response::from_string("").with_status_code(404).boxed();
+    };
+
+    let song = crate::data::SongEntry {
+        title: r.title.into(),
+        artists: r.artists.into_iter().map(|a| a.into()).collect(),
+        album: r.album.into(),
+        genres: r.genres.into_iter().map(|g| g.into()).collect(),
+        track: r.track,
+        ..song
+    };
+
+    db.update_songs(std::slice::from_ref(&song));
+    crate::tags::try_write_tags(&song);
 
-    Response::from_string("{}").with_status_code(200).boxed()
+    crate::to_json!(&song)
 }
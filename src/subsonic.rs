@@ -0,0 +1,648 @@
+//! A subset of the Subsonic/OpenSubsonic REST API, so existing mobile clients
+//! can browse and stream the library. Served under `/rest/`.
+//!
+//! Subsonic has artist and album IDs, jukbx does not, so they are derived by
+//! hashing the artist name and album name. Song IDs are the `song_path`.
+
+use crate::{
+    data::{Database, SongEntry},
+    macros::escape_xml,
+    transcode::{self, TranscodeFormat},
+};
+use lofty::{file::TaggedFileExt, picture::PictureType, probe::Probe};
+use md5::Md5;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, fs, fs::File, io::Read, path::PathBuf};
+use tiny_http::{Header, Method, Request, Response, ResponseBox};
+
+const API_VERSION: &str = "1.16.1";
+
+enum ApiError {
+    MissingParameter(&'static str),
+    WrongCredentials,
+    NotFound(&'static str),
+}
+
+impl ApiError {
+    fn code(&self) -> u32 {
+        match self {
+            ApiError::MissingParameter(_) => 10,
+            ApiError::WrongCredentials => 40,
+            ApiError::NotFound(_) => 70,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ApiError::MissingParameter(name) => format!("Required parameter '{name}' is missing"),
+            ApiError::WrongCredentials => "Wrong username or password".to_string(),
+            ApiError::NotFound(what) => format!("{what} not found"),
+        }
+    }
+}
+
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn require(&self, name: &'static str) -> Result<&str, ApiError> {
+        self.get(name).ok_or(ApiError::MissingParameter(name))
+    }
+
+    fn get_usize(&self, name: &str, default: usize) -> usize {
+        self.get(name).and_then(|v| v.parse().ok()).unwrap_or(default)
+    }
+}
+
+fn parse_form(form: &str, params: &mut Vec<(String, String)>) {
+    for kv in form.split('&').filter(|kv| !kv.is_empty()) {
+        let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+        let decode = |s: &str| url_escape::decode_component(&s.replace('+', " ")).into_owned();
+        params.push((decode(k), decode(v)));
+    }
+}
+
+/// Subsonic clients send parameters in the query string, or as a form body
+/// when they use POST.
+fn read_params(req: &mut Request) -> Params {
+    let mut params = Vec::new();
+
+    if let Some((_, query)) = req.url().split_once('?') {
+        parse_form(query, &mut params);
+    }
+
+    if *req.method() == Method::Post {
+        let mut body = String::new();
+        if req.as_reader().read_to_string(&mut body).is_ok() {
+            parse_form(&body, &mut params);
+        }
+    }
+
+    Params(params)
+}
+
+pub(crate) fn handle(db: &Database, req: &mut Request) -> ResponseBox {
+    let url = req.url();
+    let path = url.split('?').next().unwrap_or(url);
+    let method = path
+        .trim_start_matches("/rest/")
+        .trim_end_matches(".view")
+        .to_string();
+
+    let params = read_params(req);
+
+    let user = match authenticate(db, &params) {
+        Ok(user) => user,
+        Err(e) => {
+            log::warn!("Subsonic auth failed: {}", e.message());
+            return encode_error(&params, e);
+        }
+    };
+
+    let result = match method.as_str() {
+        "ping" => Ok(json!({})),
+        "getLicense" => Ok(json!({ "license": { "valid": true } })),
+        "getOpenSubsonicExtensions" => Ok(json!({ "openSubsonicExtensions": [] })),
+        "getMusicFolders" => Ok(json!({
+            "musicFolders": { "musicFolder": [{ "id": 1, "name": "jukbx" }] }
+        })),
+        "getArtists" => Ok(get_artists(db)),
+        "getArtist" => get_artist(db, &params),
+        "getAlbum" => get_album(db, &params),
+        "getSong" => get_song(db, &params),
+        "search3" => Ok(search3(db, &params)),
+        "scrobble" => scrobble(db, &user, &params),
+        "stream" | "download" => return stream(db, req, &params),
+        "getCoverArt" => return get_cover_art(db, &params),
+        _ => {
+            return Response::from_string("Not found")
+                .with_status_code(404)
+                .boxed();
+        }
+    };
+
+    match result {
+        Ok(payload) => encode_ok(&params, payload),
+        Err(e) => encode_error(&params, e),
+    }
+}
+
+fn authenticate(db: &Database, params: &Params) -> Result<String, ApiError> {
+    let user = params.require("u")?;
+
+    if let (Some(token), Some(salt)) = (params.get("t"), params.get("s")) {
+        let api_pass = db
+            .get_api_password(user)
+            .ok_or(ApiError::WrongCredentials)?;
+
+        let mut hasher = Md5::new();
+        hasher.update(api_pass.as_bytes());
+        hasher.update(salt.as_bytes());
+        let expected = to_hex(&hasher.finalize());
+
+        if !expected.eq_ignore_ascii_case(token) {
+            return Err(ApiError::WrongCredentials);
+        }
+
+        return Ok(user.to_string());
+    }
+
+    let pass = params.require("p")?;
+    let pass = match pass.strip_prefix("enc:") {
+        Some(hex) => from_hex(hex).ok_or(ApiError::WrongCredentials)?,
+        None => pass.to_string(),
+    };
+
+    if db.get_api_password(user).as_deref() == Some(pass.as_str()) {
+        return Ok(user.to_string());
+    }
+
+    // Clients that send the password in plain text may just as well use the
+    // regular login password
+    let mut hasher = Sha256::new();
+    hasher.update(pass.as_bytes());
+    let base64_pass = base64::encode(hasher.finalize());
+
+    db.get_user(user, &base64_pass)
+        .ok_or(ApiError::WrongCredentials)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<String> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    String::from_utf8(bytes).ok()
+}
+
+fn encode_ok(params: &Params, payload: Value) -> ResponseBox {
+    let mut body = Map::new();
+    body.insert("status".into(), "ok".into());
+    body.insert("version".into(), API_VERSION.into());
+    body.insert("type".into(), "jukbx".into());
+    body.insert("serverVersion".into(), env!("CARGO_PKG_VERSION").into());
+    body.insert("openSubsonic".into(), true.into());
+    if let Value::Object(payload) = payload {
+        body.extend(payload);
+    }
+
+    encode(params, Value::Object(body))
+}
+
+fn encode_error(params: &Params, error: ApiError) -> ResponseBox {
+    let body = json!({
+        "status": "failed",
+        "version": API_VERSION,
+        "type": "jukbx",
+        "serverVersion": env!("CARGO_PKG_VERSION"),
+        "openSubsonic": true,
+        "error": { "code": error.code(), "message": error.message() },
+    });
+
+    encode(params, body)
+}
+
+fn encode(params: &Params, body: Value) -> ResponseBox {
+    match params.get("f") {
+        Some("json") => {
+            let json = json!({ "subsonic-response": body }).to_string();
+            Response::from_string(json)
+                .with_header(
+                    Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap(),
+                )
+                .with_status_code(200)
+                .boxed()
+        }
+        _ => {
+            let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+            to_xml("subsonic-response", &body, &mut xml);
+            Response::from_string(xml)
+                .with_header(
+                    Header::from_bytes(&b"Content-Type"[..], &b"text/xml; charset=utf-8"[..])
+                        .unwrap(),
+                )
+                .with_status_code(200)
+                .boxed()
+        }
+    }
+}
+
+/// Writes a JSON response tree as Subsonic XML: scalars become attributes,
+/// objects become child elements and arrays become repeated child elements.
+fn to_xml(name: &str, value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            out.push('<');
+            out.push_str(name);
+            if name == "subsonic-response" {
+                out.push_str(" xmlns=\"http://subsonic.org/restapi\"");
+            }
+            for (k, v) in map {
+                let text = match v {
+                    Value::String(s) => s.clone(),
+                    Value::Number(n) => n.to_string(),
+                    Value::Bool(b) => b.to_string(),
+                    _ => continue,
+                };
+                out.push_str(&format!(" {k}=\"{}\"", escape_xml(&text)));
+            }
+
+            let children = map
+                .iter()
+                .filter(|(_, v)| v.is_object() || v.is_array())
+                .collect::<Vec<_>>();
+            if children.is_empty() {
+                out.push_str("/>");
+                return;
+            }
+
+            out.push('>');
+            for (k, v) in children {
+                to_xml(k, v, out);
+            }
+            out.push_str(&format!("</{name}>"));
+        }
+        Value::Array(items) => {
+            for item in items {
+                to_xml(name, item, out);
+            }
+        }
+        Value::Null => {}
+        scalar => {
+            let text = scalar.as_str().map(String::from).unwrap_or(scalar.to_string());
+            out.push_str(&format!("<{name}>{}</{name}>", escape_xml(&text)));
+        }
+    }
+}
+
+fn short_hash(text: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(text.as_bytes());
+    to_hex(&hasher.finalize()[..8])
+}
+
+fn get_artist_name<'a>(song: &'a SongEntry) -> &'a str {
+    song.artists
+        .first()
+        .map(|a| &a[..])
+        .filter(|a| !a.is_empty())
+        .unwrap_or("[Unknown artist]")
+}
+
+fn get_album_name<'a>(song: &'a SongEntry) -> &'a str {
+    if song.album.is_empty() {
+        "[No album]"
+    } else {
+        &song.album
+    }
+}
+
+fn get_artist_id(song: &SongEntry) -> String {
+    format!("ar-{}", short_hash(get_artist_name(song)))
+}
+
+fn get_album_id(song: &SongEntry) -> String {
+    format!(
+        "al-{}",
+        short_hash(&format!("{}\x1F{}", get_artist_name(song), song.album))
+    )
+}
+
+fn get_content_type(song_path: &str) -> &'static str {
+    match song_path.rsplit('.').next().map(|e| e.to_lowercase()).as_deref() {
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg") | Some("opus") | Some("oga") => "audio/ogg",
+        Some("m4a") | Some("mp4") | Some("aac") => "audio/mp4",
+        Some("wav") => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+fn song_to_child(song: &SongEntry) -> Value {
+    let size = fs::metadata(format!("./songs/{}", song.song_path))
+        .map(|m| m.len())
+        .unwrap_or(0);
+    let suffix = song.song_path.rsplit('.').next().unwrap_or("");
+
+    let mut child = json!({
+        "id": song.song_path,
+        "parent": get_album_id(song),
+        "isDir": false,
+        "title": song.title,
+        "album": get_album_name(song),
+        "artist": song.artists.join(", "),
+        "coverArt": song.song_path,
+        "size": size,
+        "contentType": get_content_type(&song.song_path),
+        "suffix": suffix,
+        "path": format!("{}/{}/{}", get_artist_name(song), get_album_name(song), song.song_path),
+        "type": "music",
+        "mediaType": "song",
+        "albumId": get_album_id(song),
+        "artistId": get_artist_id(song),
+    });
+
+    if let Some(track) = song.track {
+        child["track"] = track.into();
+    }
+    if let Some(genre) = song.genres.first().filter(|g| !g.is_empty()) {
+        child["genre"] = genre.to_string().into();
+    }
+    if let Some(duration) = song.duration_secs {
+        child["duration"] = duration.into();
+    }
+    if let Some(mbid) = &song.recording_mbid {
+        child["musicBrainzId"] = mbid.to_string().into();
+    }
+
+    child
+}
+
+fn album_to_json(songs: &[&SongEntry]) -> Value {
+    let first = songs[0];
+    let duration: u64 = songs
+        .iter()
+        .filter_map(|s| s.duration_secs)
+        .sum();
+
+    json!({
+        "id": get_album_id(first),
+        "name": get_album_name(first),
+        "artist": get_artist_name(first),
+        "artistId": get_artist_id(first),
+        "coverArt": first.song_path,
+        "songCount": songs.len(),
+        "duration": duration,
+    })
+}
+
+/// Groups songs by album ID, keeping the albums in a stable order.
+fn group_albums<'a>(songs: impl Iterator<Item = &'a SongEntry<'static>>) -> BTreeMap<String, Vec<&'a SongEntry<'static>>> {
+    let mut albums: BTreeMap<String, Vec<&SongEntry>> = BTreeMap::new();
+    for song in songs {
+        albums.entry(get_album_id(song)).or_default().push(song);
+    }
+    albums
+}
+
+fn get_artists(db: &Database) -> Value {
+    let songs = db.get_all_songs();
+
+    let mut artists: BTreeMap<String, Vec<&SongEntry>> = BTreeMap::new();
+    for song in &songs {
+        artists
+            .entry(get_artist_name(song).to_string())
+            .or_default()
+            .push(song);
+    }
+
+    let mut indexes: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for (name, songs) in &artists {
+        let letter = name
+            .chars()
+            .next()
+            .filter(|c| c.is_alphabetic())
+            .map(|c| c.to_uppercase().to_string())
+            .unwrap_or("#".to_string());
+
+        indexes.entry(letter).or_default().push(json!({
+            "id": get_artist_id(songs[0]),
+            "name": name,
+            "albumCount": group_albums(songs.iter().copied()).len(),
+        }));
+    }
+
+    let index = indexes
+        .into_iter()
+        .map(|(name, artist)| json!({ "name": name, "artist": artist }))
+        .collect::<Vec<_>>();
+
+    json!({ "artists": { "ignoredArticles": "", "index": index } })
+}
+
+fn get_artist(db: &Database, params: &Params) -> Result<Value, ApiError> {
+    let id = params.require("id")?;
+    let songs = db.get_all_songs();
+
+    let albums = group_albums(songs.iter().filter(|s| get_artist_id(s) == id));
+    let Some(first) = albums.values().next().map(|songs| songs[0]) else {
+        return Err(ApiError::NotFound("Artist"));
+    };
+
+    Ok(json!({
+        "artist": {
+            "id": id,
+            "name": get_artist_name(first),
+            "albumCount": albums.len(),
+            "album": albums.values().map(|songs| album_to_json(songs)).collect::<Vec<_>>(),
+        }
+    }))
+}
+
+fn get_album(db: &Database, params: &Params) -> Result<Value, ApiError> {
+    let id = params.require("id")?;
+    let songs = db.get_all_songs();
+
+    let album_songs = songs
+        .iter()
+        .filter(|s| get_album_id(s) == id)
+        .collect::<Vec<_>>();
+    if album_songs.is_empty() {
+        return Err(ApiError::NotFound("Album"));
+    }
+
+    let mut album = album_to_json(&album_songs);
+    album["song"] = album_songs.iter().map(|s| song_to_child(s)).collect();
+
+    Ok(json!({ "album": album }))
+}
+
+fn get_song(db: &Database, params: &Params) -> Result<Value, ApiError> {
+    let id = params.require("id")?;
+    let songs = db.get_all_songs();
+
+    let song = songs
+        .iter()
+        .find(|s| s.song_path == id)
+        .ok_or(ApiError::NotFound("Song"))?;
+
+    Ok(json!({ "song": song_to_child(song) }))
+}
+
+fn search3(db: &Database, params: &Params) -> Value {
+    // Clients ask for "" to sync the whole library
+    let query = params
+        .get("query")
+        .unwrap_or("")
+        .trim()
+        .trim_matches('"')
+        .to_uppercase();
+    let matches = |text: &str| query.is_empty() || text.to_uppercase().contains(&query);
+
+    let songs = db.get_all_songs();
+
+    let mut artists: BTreeMap<String, &SongEntry> = BTreeMap::new();
+    for song in songs.iter().filter(|s| matches(get_artist_name(s))) {
+        artists.entry(get_artist_id(song)).or_insert(song);
+    }
+    let artists = artists
+        .values()
+        .skip(params.get_usize("artistOffset", 0))
+        .take(params.get_usize("artistCount", 20))
+        .map(|s| json!({ "id": get_artist_id(s), "name": get_artist_name(s) }))
+        .collect::<Vec<_>>();
+
+    let albums = group_albums(songs.iter().filter(|s| matches(&s.album)));
+    let albums = albums
+        .values()
+        .skip(params.get_usize("albumOffset", 0))
+        .take(params.get_usize("albumCount", 20))
+        .map(|songs| album_to_json(songs))
+        .collect::<Vec<_>>();
+
+    let songs = songs
+        .iter()
+        .filter(|s| {
+            matches(&s.title) || matches(&s.album) || s.artists.iter().any(|a| matches(a))
+        })
+        .skip(params.get_usize("songOffset", 0))
+        .take(params.get_usize("songCount", 20))
+        .map(song_to_child)
+        .collect::<Vec<_>>();
+
+    json!({ "searchResult3": { "artist": artists, "album": albums, "song": songs } })
+}
+
+/// Only submissions count as plays, "now playing" notifications are ignored.
+fn scrobble(db: &Database, user: &str, params: &Params) -> Result<Value, ApiError> {
+    let id = params.require("id")?;
+
+    if db.get_song_by_path(id).is_none() {
+        return Err(ApiError::NotFound("Song"));
+    }
+
+    if params.get("submission") != Some("false") {
+        crate::history::record_play(db, id, user, "subsonic");
+    }
+
+    Ok(json!({}))
+}
+
+/// Transcodes when the client asks for `format=opus`, at `maxBitRate` if it
+/// set one. Any other format, `raw` included, gets the file as it is.
+fn stream(db: &Database, req: &Request, params: &Params) -> ResponseBox {
+    let Some(id) = params.get("id") else {
+        return encode_error(params, ApiError::MissingParameter("id"));
+    };
+
+    let songs = db.get_all_songs();
+    let Some(song) = songs.iter().find(|s| s.song_path == id) else {
+        return encode_error(params, ApiError::NotFound("Song"));
+    };
+
+    let path = match params.get("format").and_then(TranscodeFormat::parse) {
+        Some(format) => {
+            // 0 means no limit
+            let bitrate = match params.get_usize("maxBitRate", 0) as u32 {
+                0 => transcode::DEFAULT_BITRATE_KBPS,
+                bitrate => bitrate.clamp(16, 256),
+            };
+
+            match transcode::get_transcoded(&song.song_path, format, bitrate) {
+                Ok(path) => path,
+                Err(e) => {
+                    log::error!("Failed to transcode {}: {e:?}", song.song_path);
+                    return Response::from_string("").with_status_code(500).boxed();
+                }
+            }
+        }
+        None => PathBuf::from(format!("./songs/{}", song.song_path)),
+    };
+
+    let Ok(file) = File::open(path) else {
+        return encode_error(params, ApiError::NotFound("Song file"));
+    };
+
+    crate::song::serve_file(req, file)
+}
+
+fn get_embedded_cover(song_path: &str) -> Option<(Vec<u8>, String)> {
+    let file = Probe::open(format!("./songs/{song_path}"))
+        .and_then(|p| p.read())
+        .ok()?;
+    let tag = file.primary_tag()?;
+
+    let picture = tag
+        .pictures()
+        .iter()
+        .find(|p| p.pic_type() == PictureType::CoverFront)
+        .or(tag.pictures().first())?;
+
+    let mime = picture
+        .mime_type()
+        .map(|m| m.as_str().to_string())
+        .unwrap_or("image/jpeg".to_string());
+
+    Some((picture.data().to_vec(), mime))
+}
+
+pub(crate) fn has_embedded_cover(song_path: &str) -> bool {
+    get_embedded_cover(song_path).is_some()
+}
+
+/// Covers fetched from the Cover Art Archive are kept next to the song.
+fn get_fetched_cover(song_path: &str) -> Option<(Vec<u8>, String)> {
+    let data = fs::read(crate::song::get_sidecar_path(song_path, "cover")).ok()?;
+    let mime = if data.starts_with(b"\x89PNG") {
+        "image/png"
+    } else {
+        "image/jpeg"
+    };
+
+    Some((data, mime.to_string()))
+}
+
+/// Cover art comes from the picture embedded in the song file, or else the
+/// one fetched for it. Album IDs use the first song in the album that has
+/// one.
+fn get_cover_art(db: &Database, params: &Params) -> ResponseBox {
+    let Some(id) = params.get("id") else {
+        return encode_error(params, ApiError::MissingParameter("id"));
+    };
+
+    let songs = db.get_all_songs();
+    let candidates = songs
+        .iter()
+        .filter(|s| s.song_path == id || get_album_id(s) == id);
+
+    for song in candidates {
+        let cover =
+            get_embedded_cover(&song.song_path).or_else(|| get_fetched_cover(&song.song_path));
+
+        if let Some((data, mime)) = cover {
+            return Response::from_data(data)
+                .with_header(Header::from_bytes(&b"Content-Type"[..], mime.as_bytes()).unwrap())
+                .with_status_code(200)
+                .boxed();
+        }
+    }
+
+    encode_error(params, ApiError::NotFound("Cover art"))
+}
@@ -0,0 +1,87 @@
+//! Writes a song's metadata back into its file, so a downloaded copy
+//! carries the same title, artists, album, genres and track number as
+//! `songs.csv`. A cover fetched by the `cover` job is embedded if the file
+//! has none.
+//!
+//! Songs scanned in place are links to someone else's files, those are left
+//! alone.
+
+use std::{
+    borrow::Cow,
+    fs,
+    path::Path,
+    sync::{LazyLock, Mutex},
+};
+
+use lofty::{
+    config::WriteOptions,
+    file::TaggedFileExt,
+    picture::{MimeType, Picture, PictureType},
+    tag::{Accessor, ItemKey, ItemValue, Tag, TagItem},
+};
+
+use crate::{data::SongEntry, song::get_sidecar_path};
+
+/// Keeps an edit and a job from saving the same file at once.
+pub(crate) static TAG_MUTEX: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// Replaces all values of `key` with `values`, one item each.
+fn set_list(tag: &mut Tag, key: ItemKey, values: &[Cow<str>]) {
+    tag.remove_key(&key);
+    for value in values {
+        tag.push(TagItem::new(key.clone(), ItemValue::Text(value.to_string())));
+    }
+}
+
+pub(crate) fn write_tags(song: &SongEntry) -> anyhow::Result<()> {
+    let path = format!("./songs/{}", song.song_path);
+    if fs::symlink_metadata(&path)?.file_type().is_symlink() {
+        log::debug!("Not writing tags to {path}, it was scanned in place");
+        return Ok(());
+    }
+
+    let _guard = TAG_MUTEX.lock().unwrap();
+
+    let mut file = lofty::read_from_path(&path)?;
+    if file.primary_tag().is_none() {
+        let tag_type = file.primary_tag_type();
+        file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = file.primary_tag_mut().unwrap();
+
+    tag.set_title(song.title.to_string());
+    set_list(tag, ItemKey::TrackArtist, &song.artists);
+    if song.album.is_empty() {
+        tag.remove_album();
+    } else {
+        tag.set_album(song.album.to_string());
+    }
+    set_list(tag, ItemKey::Genre, &song.genres);
+    match song.track {
+        Some(track) => tag.set_track(track),
+        None => tag.remove_track(),
+    }
+
+    if tag.pictures().is_empty() {
+        if let Ok(cover) = fs::read(get_sidecar_path(&song.song_path, "cover")) {
+            let mime = if cover.starts_with(b"\x89PNG") {
+                MimeType::Png
+            } else {
+                MimeType::Jpeg
+            };
+            tag.push_picture(Picture::new_unchecked(PictureType::CoverFront, Some(mime), None, cover));
+        }
+    }
+
+    file.save_to_path(Path::new(&path), WriteOptions::default())?;
+
+    Ok(())
+}
+
+/// For when the metadata is already stored and failing to tag the file
+/// shouldn't fail the request.
+pub(crate) fn try_write_tags(song: &SongEntry) {
+    if let Err(e) = write_tags(song) {
+        log::warn!("Failed to write tags to {}: {e:?}", song.song_path);
+    }
+}
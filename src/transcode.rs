@@ -0,0 +1,302 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{Condvar, LazyLock, Mutex},
+};
+
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use opus::{Application, Bitrate, Channels, Encoder};
+
+use crate::audio;
+
+const OPUS_RATE: u32 = 48000;
+/// 20 ms at 48 kHz
+const OPUS_FRAME: usize = 960;
+/// Encoder delay of libopus in `Application::Audio` mode, skipped by decoders
+const OPUS_PRE_SKIP: u16 = 312;
+/// Bitrate when none is asked for, and the one the transcode job prepares
+pub(crate) const DEFAULT_BITRATE_KBPS: u32 = 96;
+
+pub(crate) enum TranscodeFormat {
+    Opus,
+}
+
+impl TranscodeFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "opus" => Some(TranscodeFormat::Opus),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            TranscodeFormat::Opus => "opus",
+        }
+    }
+}
+
+/// Transcodes that are running, by cache path. A request for one of them
+/// waits for it instead of doing the work twice, other songs go ahead.
+static IN_PROGRESS: LazyLock<Mutex<HashSet<PathBuf>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+/// Notified whenever a transcode leaves `IN_PROGRESS`
+static TRANSCODE_DONE: Condvar = Condvar::new();
+
+/// Takes a transcode out of `IN_PROGRESS` when it ends, even on an error.
+struct InProgress(PathBuf);
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        IN_PROGRESS.lock().unwrap().remove(&self.0);
+        TRANSCODE_DONE.notify_all();
+    }
+}
+
+/// Returns the path of a cached transcode of `song_path`, creating it first
+/// if needed.
+pub(crate) fn get_transcoded(
+    song_path: &str,
+    format: TranscodeFormat,
+    bitrate_kbps: u32,
+) -> anyhow::Result<PathBuf> {
+    let cache_path = PathBuf::from(format!(
+        "./cache/transcode/{song_path}.{bitrate_kbps}k.{}",
+        format.extension()
+    ));
+
+    if cache_path.exists() {
+        return Ok(cache_path);
+    }
+
+    let _guard = {
+        let mut in_progress = IN_PROGRESS.lock().unwrap();
+        while in_progress.contains(&cache_path) {
+            in_progress = TRANSCODE_DONE.wait(in_progress).unwrap();
+        }
+
+        if cache_path.exists() {
+            return Ok(cache_path);
+        }
+
+        in_progress.insert(cache_path.clone());
+        InProgress(cache_path.clone())
+    };
+
+    fs::create_dir_all("./cache/transcode")?;
+
+    let source = PathBuf::from(format!("./songs/{song_path}"));
+    let temp_path = cache_path.with_extension("tmp");
+
+    log::info!("Transcoding {song_path} to {bitrate_kbps} kbps {}", format.extension());
+
+    let result = match format {
+        TranscodeFormat::Opus => transcode_to_opus(&source, &temp_path, bitrate_kbps),
+    };
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    fs::rename(&temp_path, &cache_path)?;
+
+    Ok(cache_path)
+}
+
+/// Streaming linear interpolation, good enough for low-bandwidth listening.
+struct Resampler {
+    step: f64,
+    pos: f64,
+    prev: Vec<f32>,
+    channels: usize,
+}
+
+impl Resampler {
+    fn new(from_rate: u32, to_rate: u32, channels: usize) -> Self {
+        Resampler {
+            step: from_rate as f64 / to_rate as f64,
+            pos: 0.0,
+            prev: vec![0.0; channels],
+            channels,
+        }
+    }
+
+    fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let frames = input.len() / self.channels;
+        if frames == 0 {
+            return;
+        }
+
+        // Position 0 is the last frame of the previous call, position n is
+        // input frame n - 1
+        while self.pos < frames as f64 {
+            let i = self.pos as usize;
+            let frac = (self.pos - i as f64) as f32;
+
+            for c in 0..self.channels {
+                let a = if i == 0 {
+                    self.prev[c]
+                } else {
+                    input[(i - 1) * self.channels + c]
+                };
+                let b = input[i * self.channels + c];
+                output.push(a + (b - a) * frac);
+            }
+
+            self.pos += self.step;
+        }
+
+        self.pos -= frames as f64;
+        self.prev
+            .copy_from_slice(&input[(frames - 1) * self.channels..frames * self.channels]);
+    }
+}
+
+fn transcode_to_opus(source: &Path, destination: &Path, bitrate_kbps: u32) -> anyhow::Result<()> {
+    let mut encoder: Option<(Encoder, Resampler, usize)> = None;
+    let mut source_rate = OPUS_RATE;
+    let mut pcm = Vec::new();
+    let mut mixed = Vec::new();
+    let mut input_samples = 0u64;
+
+    let mut writer = PacketWriter::new(BufWriter::new(File::create(destination)?));
+    let serial: u32 = rand::random();
+    let mut granule = 0u64;
+    let mut packet = vec![0u8; 4000];
+    let mut error = None;
+
+    audio::decode_file(source, |samples, channels, rate| {
+        if error.is_some() {
+            return;
+        }
+
+        if encoder.is_none() {
+            let out_channels = channels.min(2);
+            let opus_channels = if out_channels == 1 {
+                Channels::Mono
+            } else {
+                Channels::Stereo
+            };
+
+            let result = Encoder::new(OPUS_RATE, opus_channels, Application::Audio)
+                .and_then(|mut e| {
+                    e.set_bitrate(Bitrate::Bits(bitrate_kbps as i32 * 1000))?;
+                    Ok(e)
+                })
+                .map_err(anyhow::Error::from)
+                .and_then(|e| {
+                    write_opus_headers(&mut writer, serial, out_channels as u8, rate)?;
+                    Ok(e)
+                });
+
+            match result {
+                Ok(e) => {
+                    source_rate = rate;
+                    encoder = Some((e, Resampler::new(rate, OPUS_RATE, out_channels), out_channels));
+                }
+                Err(e) => {
+                    error = Some(e);
+                    return;
+                }
+            }
+        }
+
+        let Some((opus, resampler, out_channels)) = &mut encoder else {
+            return;
+        };
+
+        // Keep the first two channels, Opus mapping family 0 has no more
+        mixed.clear();
+        for frame in samples.chunks_exact(channels) {
+            mixed.extend_from_slice(&frame[..*out_channels]);
+        }
+        input_samples += (samples.len() / channels) as u64;
+        resampler.process(&mixed, &mut pcm);
+
+        let frame_len = OPUS_FRAME * *out_channels;
+        let mut offset = 0;
+        while pcm.len() - offset >= frame_len {
+            let result = opus
+                .encode_float(&pcm[offset..offset + frame_len], &mut packet)
+                .map_err(anyhow::Error::from)
+                .and_then(|len| {
+                    granule += OPUS_FRAME as u64;
+                    writer.write_packet(
+                        packet[..len].into(),
+                        serial,
+                        PacketWriteEndInfo::NormalPacket,
+                        granule,
+                    )?;
+                    Ok(())
+                });
+            if let Err(e) = result {
+                error = Some(e);
+                return;
+            }
+            offset += frame_len;
+        }
+        pcm.drain(..offset);
+    })?;
+
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    let Some((mut opus, _, out_channels)) = encoder else {
+        return Err(anyhow::anyhow!("No audio decoded"));
+    };
+
+    // Pad with silence to flush the encoder delay, the final granule position
+    // tells the decoder where the real audio ends
+    let total = OPUS_PRE_SKIP as u64 + input_samples * OPUS_RATE as u64 / source_rate as u64;
+    pcm.resize(pcm.len() + OPUS_PRE_SKIP as usize * out_channels, 0.0);
+    let frame_len = OPUS_FRAME * out_channels;
+    let padded = pcm.len().div_ceil(frame_len) * frame_len;
+    pcm.resize(padded, 0.0);
+
+    let frames = pcm.chunks_exact(frame_len).collect::<Vec<_>>();
+    for (i, frame) in frames.iter().enumerate() {
+        let len = opus.encode_float(frame, &mut packet)?;
+        granule += OPUS_FRAME as u64;
+
+        let is_last = i + 1 == frames.len();
+        let end_info = if is_last {
+            PacketWriteEndInfo::EndStream
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        let packet_granule = if is_last { granule.min(total) } else { granule };
+
+        writer.write_packet(packet[..len].into(), serial, end_info, packet_granule)?;
+    }
+
+    Ok(())
+}
+
+fn write_opus_headers<W: std::io::Write>(
+    writer: &mut PacketWriter<W>,
+    serial: u32,
+    channels: u8,
+    input_rate: u32,
+) -> anyhow::Result<()> {
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1);
+    head.push(channels);
+    head.extend_from_slice(&OPUS_PRE_SKIP.to_le_bytes());
+    head.extend_from_slice(&input_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(0);
+    writer.write_packet(head.into(), serial, PacketWriteEndInfo::EndPage, 0)?;
+
+    let vendor = b"jukbx";
+    let mut tags = Vec::new();
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&0u32.to_le_bytes());
+    writer.write_packet(tags.into(), serial, PacketWriteEndInfo::EndPage, 0)?;
+
+    Ok(())
+}
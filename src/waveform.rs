@@ -0,0 +1,106 @@
+use std::{fs, path::Path};
+
+use serde::Serialize;
+use tiny_http::{Header, Request, Response, ResponseBox};
+
+use crate::{audio, data::Database, song::get_sidecar_path};
+
+/// Number of peaks stored per song, enough for a full-width waveform
+const PEAK_COUNT: usize = 800;
+/// Frames folded into one intermediate peak while decoding
+const BLOCK_FRAMES: usize = 256;
+
+/// Decodes a song and writes its peaks next to it as one byte per peak.
+pub(crate) fn generate(song_path: &str) -> anyhow::Result<Vec<u8>> {
+    let mut blocks = Vec::new();
+    let mut block_peak = 0.0f32;
+    let mut block_len = 0;
+
+    audio::decode_file(Path::new(&format!("./songs/{song_path}")), |samples, channels, _| {
+        for frame in samples.chunks_exact(channels) {
+            for s in frame {
+                block_peak = block_peak.max(s.abs());
+            }
+            block_len += 1;
+
+            if block_len == BLOCK_FRAMES {
+                blocks.push(block_peak);
+                block_peak = 0.0;
+                block_len = 0;
+            }
+        }
+    })?;
+    if block_len > 0 {
+        blocks.push(block_peak);
+    }
+
+    let peaks = downsample(&blocks, PEAK_COUNT);
+
+    fs::write(get_sidecar_path(song_path, "peaks"), &peaks)?;
+
+    Ok(peaks)
+}
+
+fn downsample(blocks: &[f32], count: usize) -> Vec<u8> {
+    if blocks.is_empty() {
+        return vec![];
+    }
+
+    let count = count.min(blocks.len());
+    (0..count)
+        .map(|i| {
+            let start = i * blocks.len() / count;
+            let end = ((i + 1) * blocks.len() / count).max(start + 1);
+            let peak = blocks[start..end].iter().fold(0.0f32, |a, &b| a.max(b));
+            (peak.min(1.0) * 255.0).round() as u8
+        })
+        .collect()
+}
+
+pub(crate) fn get_peaks(song_path: &str) -> anyhow::Result<Vec<u8>> {
+    match fs::read(get_sidecar_path(song_path, "peaks")) {
+        Ok(peaks) => Ok(peaks),
+        // Songs from before waveforms existed get theirs on first request
+        Err(_) => generate(song_path),
+    }
+}
+
+/// Serves `/api/waveform/{song}` as `{"peaks": [0-255, ...]}`, or as raw
+/// bytes with `?format=bin`.
+pub(crate) fn get_waveform(db: &Database, req: &mut Request) -> ResponseBox {
+    let _ = crate::try_data_access!(db, req);
+
+    let url = req.url();
+    let Some(song) = url.split('?').next().and_then(|p| p.rsplit('/').next()) else {
+        return Response::from_string("").with_status_code(404).boxed();
+    };
+    let song = url_escape::decode(song).into_owned();
+
+    if db.get_song_by_path(&song).is_none() || !Path::new(&format!("./songs/{song}")).is_file() {
+        return Response::from_string("").with_status_code(404).boxed();
+    }
+
+    let peaks = match get_peaks(&song) {
+        Ok(peaks) => peaks,
+        Err(e) => {
+            log::error!("Failed to generate waveform for {song}: {e:?}");
+            return Response::from_string("").with_status_code(500).boxed();
+        }
+    };
+
+    if crate::macros::get_query_param(url, "format").as_deref() == Some("bin") {
+        return Response::from_data(peaks)
+            .with_header(
+                Header::from_bytes(&b"Content-Type"[..], &b"application/octet-stream"[..]).unwrap(),
+            )
+            .with_status_code(200)
+            .boxed();
+    }
+
+    #[derive(Serialize)]
+    struct WaveformResponse {
+        peaks: Vec<u8>,
+    }
+
+    crate::to_json!(&WaveformResponse { peaks })
+}